@@ -1,7 +1,12 @@
-use std::fs::{create_dir_all, read_to_string, File};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{self, create_dir_all, read_to_string, remove_file, rename, File};
 use std::io::Write;
+use std::path::{Path, PathBuf};
 
 use pwvlt::Config;
+use toml::Value;
+use toml_edit::{table, value, DocumentMut, TableLike};
 
 use crate::error::Error;
 
@@ -18,32 +23,358 @@ symbols = true
 strict = true
 ";
 
-pub fn load_config() -> Result<Config, Error> {
-    let home = home::home_dir().ok_or(Error::HomeNotFound)?;
-    // config will be loaded from ~/.config/pwvlt/config.toml
-    let config_dir = home.join(".config").join("pwvlt");
-    if !config_dir.exists() {
-        log::info!("Creating {}", config_dir.display());
-        create_dir_all(&config_dir)?;
+/// A config shared by all the users of a machine. Values from the user's
+/// config take precedence over the ones found here.
+const SYSTEM_CONFIG: &str = "/etc/pwvlt/config.toml";
+
+/// The effective value of every config key, along with where it was set
+/// (`default` if no layer sets it).
+pub type Origins = BTreeMap<String, (Value, String)>;
+
+enum EnvKind {
+    String,
+    /// A comma separated list of strings.
+    List,
+    Integer,
+    Bool,
+}
+
+/// Environment variables which override a single config value, along with
+/// the table and key they override.
+const ENV_OVERRIDES: [(&str, &str, &str, EnvKind); 17] = [
+    ("PWVLT_BACKENDS", "general", "backends", EnvKind::List),
+    ("PWVLT_LOOKUP", "general", "lookup", EnvKind::String),
+    (
+        "PWVLT_PASSWORD_LENGTH",
+        "password",
+        "length",
+        EnvKind::Integer,
+    ),
+    (
+        "PWVLT_PASSWORD_NUMBERS",
+        "password",
+        "numbers",
+        EnvKind::Bool,
+    ),
+    (
+        "PWVLT_PASSWORD_LOWERCASE_LETTERS",
+        "password",
+        "lowercase_letters",
+        EnvKind::Bool,
+    ),
+    (
+        "PWVLT_PASSWORD_UPPERCASE_LETTERS",
+        "password",
+        "uppercase_letters",
+        EnvKind::Bool,
+    ),
+    (
+        "PWVLT_PASSWORD_SYMBOLS",
+        "password",
+        "symbols",
+        EnvKind::Bool,
+    ),
+    ("PWVLT_PASSWORD_STRICT", "password", "strict", EnvKind::Bool),
+    (
+        "PWVLT_KEYRING_COLLECTION",
+        "keyring",
+        "collection",
+        EnvKind::String,
+    ),
+    (
+        "PWVLT_KEYRING_INCLUDE_FOREIGN",
+        "keyring",
+        "include_foreign",
+        EnvKind::Bool,
+    ),
+    ("PWVLT_KEYRING_RELOCK", "keyring", "relock", EnvKind::String),
+    (
+        "PWVLT_KEYRING_RELOCK_IDLE",
+        "keyring",
+        "relock_idle",
+        EnvKind::Integer,
+    ),
+    ("PWVLT_PASS_STORE", "pass", "store", EnvKind::String),
+    ("PWVLT_PASS_GPG", "pass", "gpg", EnvKind::String),
+    ("PWVLT_KDBX_PATH", "kdbx", "path", EnvKind::String),
+    ("PWVLT_KDBX_KEY_FILE", "kdbx", "key_file", EnvKind::String),
+    ("PWVLT_KDBX_HAS_PASSWORD", "kdbx", "password", EnvKind::Bool),
+];
+
+/// Adds default usernames, e.g. `PWVLT_DEFAULT=github=alice,gitlab=bob`.
+const ENV_DEFAULT: &str = "PWVLT_DEFAULT";
+
+/// Returns the path of the user's config file. In order of precedence, the
+/// path is given by: the `--config` flag, the `PWVLT_CONFIG` environment
+/// variable, `$XDG_CONFIG_HOME/pwvlt/config.toml`, or
+/// `~/.config/pwvlt/config.toml`.
+pub fn config_path(explicit: Option<&str>) -> Result<PathBuf, Error> {
+    if let Some(path) = explicit {
+        return Ok(PathBuf::from(path));
+    }
+    if let Some(path) = env::var_os("PWVLT_CONFIG").filter(|p| !p.is_empty()) {
+        return Ok(PathBuf::from(path));
+    }
+    // relative paths in XDG_CONFIG_HOME must be ignored
+    let config_home = match env::var_os("XDG_CONFIG_HOME").map(PathBuf::from) {
+        Some(dir) if dir.is_absolute() => dir,
+        _ => home::home_dir().ok_or(Error::HomeNotFound)?.join(".config"),
+    };
+    Ok(config_home.join("pwvlt").join("config.toml"))
+}
+
+/// Recursively merges `layer` into `base`. Tables are merged key by key,
+/// every other value of `layer` replaces the one in `base`.
+fn merge(base: &mut Value, layer: Value) {
+    match (base, layer) {
+        (Value::Table(base), Value::Table(layer)) => {
+            for (key, value) in layer {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, layer) => *base = layer,
+    }
+}
+
+/// Creates a table which only contains `table.key = value`.
+fn nested(table: &str, key: &str, value: Value) -> Value {
+    let mut inner = toml::value::Table::new();
+    inner.insert(key.into(), value);
+    let mut outer = toml::value::Table::new();
+    outer.insert(table.into(), Value::Table(inner));
+    Value::Table(outer)
+}
+
+fn parse_env(var: &str, raw: &str, kind: &EnvKind) -> Result<Value, Error> {
+    let invalid = || Error::Env {
+        var: var.into(),
+        value: raw.into(),
+    };
+    let value = match kind {
+        EnvKind::String => Value::String(raw.trim().into()),
+        EnvKind::List => Value::Array(
+            raw.split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(|item| Value::String(item.into()))
+                .collect(),
+        ),
+        EnvKind::Integer => Value::Integer(raw.trim().parse().map_err(|_| invalid())?),
+        EnvKind::Bool => Value::Boolean(raw.trim().parse().map_err(|_| invalid())?),
+    };
+    Ok(value)
+}
+
+/// Returns a layer for each of the `PWVLT_*` environment variables that are
+/// set, along with the name of the variable.
+fn env_layers() -> Result<Vec<(String, Value)>, Error> {
+    let mut layers = Vec::new();
+    for (var, table, key, kind) in &ENV_OVERRIDES {
+        if let Ok(raw) = env::var(var) {
+            layers.push((
+                var.to_string(),
+                nested(table, key, parse_env(var, &raw, kind)?),
+            ));
+        }
+    }
+    if let Ok(raw) = env::var(ENV_DEFAULT) {
+        for entry in raw.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let mut parts = entry.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(service), Some(username)) => layers.push((
+                    ENV_DEFAULT.to_string(),
+                    nested(
+                        "default",
+                        service.trim(),
+                        Value::String(username.trim().into()),
+                    ),
+                )),
+                _ => {
+                    return Err(Error::Env {
+                        var: ENV_DEFAULT.into(),
+                        value: raw.clone(),
+                    })
+                }
+            }
+        }
+    }
+    Ok(layers)
+}
+
+/// Records the value and `origin` of every non-table value in `value`.
+fn record(origins: &mut Origins, prefix: &str, value: &Value, origin: &str) {
+    match value {
+        Value::Table(table) => {
+            for (key, value) in table {
+                let is_bare = key
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+                let key = match (prefix.is_empty(), is_bare) {
+                    (true, true) => key.clone(),
+                    (true, false) => format!("{:?}", key),
+                    (false, true) => format!("{}.{}", prefix, key),
+                    (false, false) => format!("{}.{:?}", prefix, key),
+                };
+                record(origins, &key, value, origin);
+            }
+        }
+        value => {
+            origins.insert(prefix.into(), (value.clone(), origin.into()));
+        }
     }
-    let config_file = config_dir.join("config.toml");
-    if !config_file.exists() {
+}
+
+/// Loads the system config, the user's config and the environment overrides
+/// (in this order, each layer overriding the previous ones).
+pub fn load_config(config_file: &Path) -> Result<(Config, Origins), Error> {
+    let system_file = Path::new(SYSTEM_CONFIG);
+    // the system config acts as a baseline, so the default one is only needed
+    // when neither exist
+    if !config_file.exists() && !system_file.exists() {
+        if let Some(config_dir) = config_file.parent().filter(|d| !d.exists()) {
+            log::info!("Creating {}", config_dir.display());
+            create_dir_all(config_dir)?;
+        }
         log::info!("Writing default config to: {}", config_file.display());
-        let mut config = File::create(&config_file)?;
+        let mut config = File::create(config_file)?;
         write!(config, "{}", DEFAULT_CONFIG)?;
     }
-    log::info!("Loading toml config into memory");
-    let toml_config = read_to_string(config_file)?;
-    toml::from_str(&toml_config).map_err(Error::from)
+    let mut layers = Vec::new();
+    for file in &[system_file, config_file] {
+        if file.exists() {
+            log::info!("Loading toml config from {}", file.display());
+            let layer = read_to_string(file)?.parse::<Value>()?;
+            layers.push((file.display().to_string(), layer));
+        }
+    }
+    layers.extend(env_layers()?);
+    let mut merged = Value::Table(Default::default());
+    let mut set = Origins::new();
+    for (origin, layer) in layers {
+        record(&mut set, "", &layer, &origin);
+        merge(&mut merged, layer);
+    }
+    let config: Config = merged.try_into()?;
+    config.validate()?;
+    // report the values pwvlt actually uses, including the defaults of the
+    // keys which no layer sets
+    let mut origins = Origins::new();
+    record(&mut origins, "", &Value::try_from(&config)?, "default");
+    for (key, (_, origin)) in set {
+        if let Some((_, effective)) = origins.get_mut(&key) {
+            *effective = origin;
+        }
+    }
+    Ok((config, origins))
+}
+
+/// Applies `edit` to the `[default]` table of the user's config file. The
+/// file is edited in place, so comments and formatting are preserved.
+fn edit_defaults<T, F>(config_file: &Path, edit: F) -> Result<T, Error>
+where
+    F: FnOnce(&mut dyn TableLike) -> T,
+{
+    let contents = if config_file.exists() {
+        read_to_string(config_file)?
+    } else {
+        String::new()
+    };
+    let mut document = contents.parse::<DocumentMut>()?;
+    let defaults = document
+        .entry("default")
+        .or_insert(table())
+        .as_table_like_mut()
+        .ok_or_else(|| Error::General("`default` must be a table.".into()))?;
+    let result = edit(defaults);
+    log::info!("Writing pwvlt config to: {}", config_file.display());
+    replace_file(config_file, &document.to_string())?;
+    Ok(result)
+}
+
+/// Writes `contents` next to `path` and renames it over `path`, so an
+/// interrupted write leaves the previous version intact. The permissions of
+/// the previous version are kept.
+pub fn replace_file(path: &Path, contents: &str) -> Result<(), Error> {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    let tmp = path.with_file_name(name);
+    let result = File::create(&tmp).and_then(|mut file| {
+        if let Ok(metadata) = fs::metadata(path) {
+            fs::set_permissions(&tmp, metadata.permissions())?;
+        }
+        file.write_all(contents.as_bytes())?;
+        file.sync_all()
+    });
+    if let Err(err) = result {
+        let _ = remove_file(&tmp);
+        return Err(err.into());
+    }
+    rename(&tmp, path)?;
+    Ok(())
 }
 
-pub fn write_config(config: &Config) -> Result<(), Error> {
-    let home = home::home_dir().ok_or(Error::HomeNotFound)?;
-    log::info!("Writing pwvlt config.");
-    write!(
-        File::create(home.join(".config").join("pwvlt").join("config.toml"))?,
-        "{}",
-        toml::to_string(config)?
-    )
-    .map_err(Error::from)
+pub fn set_default(config_file: &Path, service: &str, username: &str) -> Result<(), Error> {
+    edit_defaults(config_file, |defaults| {
+        defaults.insert(service, value(username));
+    })
+}
+
+/// Removes the default username of `service`. Returns whether the user's
+/// config contained one.
+pub fn unset_default(config_file: &Path, service: &str) -> Result<bool, Error> {
+    edit_defaults(config_file, |defaults| defaults.remove(service).is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn origins_include_defaults() {
+        let dir = env::temp_dir().join(format!("pwvlt-config-{}", std::process::id()));
+        create_dir_all(&dir).unwrap();
+        let config_file = dir.join("config.toml");
+        write!(
+            File::create(&config_file).unwrap(),
+            "{}\n[timeouts.keyring]\nconnect = 3\n",
+            DEFAULT_CONFIG
+        )
+        .unwrap();
+        let (_, origins) = load_config(&config_file).unwrap();
+        let origin = |key: &str| origins[key].1.as_str();
+        let user = config_file.display().to_string();
+        assert_eq!(origin("password.length"), user);
+        assert_eq!(origin("timeouts.keyring.connect"), user);
+        // keys which aren't set anywhere show the value pwvlt uses
+        assert_eq!(origins["pass.gpg"], (Value::from("gpg"), "default".into()));
+        assert_eq!(origin("general.lookup"), "default");
+        assert_eq!(origin("keyring.include_foreign"), "default");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn set_default_keeps_the_file_and_its_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = env::temp_dir().join(format!("pwvlt-defaults-{}", std::process::id()));
+        create_dir_all(&dir).unwrap();
+        let config_file = dir.join("config.toml");
+        fs::write(&config_file, "# mine\n[default]\ngithub = \"alice\"\n").unwrap();
+        fs::set_permissions(&config_file, fs::Permissions::from_mode(0o600)).unwrap();
+
+        set_default(&config_file, "mail", "bob").unwrap();
+        assert_eq!(
+            read_to_string(&config_file).unwrap(),
+            "# mine\n[default]\ngithub = \"alice\"\nmail = \"bob\"\n"
+        );
+        let mode = fs::metadata(&config_file).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(!dir.join("config.toml.tmp").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
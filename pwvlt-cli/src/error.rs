@@ -1,4 +1,4 @@
-use pwvlt::PwvltError;
+use pwvlt::{ConfigError, PwvltError};
 
 #[derive(derive_more::From, Debug, derive_more::Display)]
 pub enum Error {
@@ -6,6 +6,14 @@ pub enum Error {
     Io(std::io::Error),
     TomlDeserialize(toml::de::Error),
     TomlSerialize(toml::ser::Error),
+    TomlEdit(toml_edit::TomlError),
+    #[display(fmt = "invalid value {:?} for {}", value, var)]
+    #[from(ignore)]
+    Env {
+        var: String,
+        value: String,
+    },
     Pwvlt(PwvltError),
-    General(Box<dyn std::error::Error>)
+    Config(ConfigError),
+    General(Box<dyn std::error::Error>),
 }
@@ -4,23 +4,52 @@ use log::error;
 use prettytable::{cell, row, Table};
 use rpassword::prompt_password_stdout;
 
-use pwvlt::{PasswordVault, PwvltError};
+use pwvlt::util::{entropy_bits, random_passwords};
+use pwvlt::{
+    Attempt, Backend, NitrokeyBackend, PasswordVault, Pin, PinRequest, PwvltError, SerialNumber,
+    Slot,
+};
 
+use std::env;
+use std::fs::read_to_string;
 use std::io::{stdout, Write};
+use std::path::Path;
 use std::thread::sleep;
 use std::time::Duration;
 
 mod config;
-use config::write_config;
+use config::{set_default, unset_default};
 mod error;
 use error::Error;
+mod usage;
+use usage::{record_use, Usage};
 mod util;
-use util::{looping_prompt, print_slots, prompt_string};
+use util::{
+    confirm, looping_prompt, print_config, print_defaults, print_device_report, print_devices,
+    print_slots, prompt_new_pin, prompt_string,
+};
 
 const DEFAULT_TIMEOUT: u8 = 7;
 
-pub fn handle_get(pv: PasswordVault, service: &str, username: &str) -> Result<(), Error> {
-    let password = pv.password(&service, &username)?;
+/// Flags which override the boolean fields of the configured password policy.
+const POLICY_OVERRIDES: [(&str, &str); 5] = [
+    ("numbers", "Override whether passwords contain numbers."),
+    (
+        "lowercase",
+        "Override whether passwords contain lowercase letters.",
+    ),
+    (
+        "uppercase",
+        "Override whether passwords contain uppercase letters.",
+    ),
+    ("symbols", "Override whether passwords contain symbols."),
+    (
+        "strict",
+        "Override whether every character class must be used.",
+    ),
+];
+
+fn copy_to_clipboard(password: String) -> Result<(), Error> {
     let mut ctx: ClipboardContext = ClipboardProvider::new()?;
     ctx.set_contents(password)?;
     for i in (0..DEFAULT_TIMEOUT).rev() {
@@ -33,41 +62,280 @@ pub fn handle_get(pv: PasswordVault, service: &str, username: &str) -> Result<()
     Ok(())
 }
 
+pub fn handle_get(pv: PasswordVault, service: &str, username: &str) -> Result<(), Error> {
+    let password = pv.password(service, username)?;
+    record_use(service, username);
+    copy_to_clipboard(password)
+}
+
+pub fn handle_generate(mut config: pwvlt::Config, args: &ArgMatches) -> Result<(), Error> {
+    let count = match args.value_of("generate") {
+        Some(count) => count
+            .parse::<usize>()
+            .map_err(|e| Error::General(Box::new(e)))?,
+        None => 1,
+    };
+    if count == 0 {
+        return Err(Error::General(
+            "The number of passwords to generate must be at least 1.".into(),
+        ));
+    }
+    let policy = &mut config.password;
+    if let Some(length) = args.value_of("length") {
+        policy.length = length
+            .parse::<usize>()
+            .map_err(|e| Error::General(Box::new(e)))?;
+    }
+    let flags = [
+        ("numbers", &mut policy.numbers),
+        ("lowercase", &mut policy.lowercase_letters),
+        ("uppercase", &mut policy.uppercase_letters),
+        ("symbols", &mut policy.symbols),
+        ("strict", &mut policy.strict),
+    ];
+    for (name, field) in flags {
+        if let Some(value) = args.value_of(name) {
+            *field = value == "true";
+        }
+    }
+    let mut passwords = random_passwords(&config.password, count)?;
+    if args.is_present("copy") {
+        if passwords.len() > 1 {
+            log::warn!("Only the first generated password is copied to the clipboard.");
+        }
+        copy_to_clipboard(passwords.swap_remove(0))?;
+    } else {
+        passwords
+            .iter()
+            .for_each(|password| println!("{}", password));
+    }
+    println!(
+        "Estimated entropy: {:.1} bits per password.",
+        entropy_bits(&config.password)
+    );
+    Ok(())
+}
+
 pub fn handle_set(
     pv: PasswordVault,
     service: &str,
     username: &str,
     password: Option<&str>,
 ) -> Result<(), Error> {
-    let backend_id = prompt_backend(&pv);
+    let mut backend_id = match pv.route(service) {
+        Some(backend_id) => {
+            log::info!(
+                "Routing {} to {}.",
+                service,
+                pv.backends()[backend_id].name()
+            );
+            backend_id
+        }
+        None => prompt_backend(&pv, None),
+    };
     let backend = &pv.backends()[backend_id];
-    let slots = backend.slots()?;
+    match backend.slot_usage()? {
+        Some(usage) if usage.programmed >= usage.total && pv.backends().len() > 1 => {
+            println!("{} is full ({}).", backend.name(), usage);
+            println!("0: Overwrite one of its slots");
+            println!("1: Move the least recently used entry to another backend");
+            println!("2: Store the password in another backend");
+            match looping_prompt("option", 2) {
+                0 => {}
+                1 => return handle_overflow(&pv, backend_id, service, username, password),
+                _ => backend_id = prompt_backend(&pv, Some(backend_id)),
+            }
+        }
+        _ => {}
+    }
+    let slot = prompt_slot(&pv, backend_id)?;
+    pv.set_password(backend_id, slot, service, username, password)?;
+    record_use(service, username);
+    Ok(())
+}
+
+/// Makes room in the full backend `backend_id` by moving its least recently
+/// used entry to another backend, then stores the password in the freed slot.
+fn handle_overflow(
+    pv: &PasswordVault,
+    backend_id: usize,
+    service: &str,
+    username: &str,
+    password: Option<&str>,
+) -> Result<(), Error> {
+    let slots = pv.backends()[backend_id].slots()?;
+    let usage = Usage::load_or_empty();
+    let lru = usage
+        .least_recently_used(&slots)
+        .ok_or_else(|| Error::General("The backend has no slot to evict.".into()))?;
+    let Slot {
+        service: old_service,
+        username: old_username,
+    } = &slots[lru];
+    let last_used = match usage.last_used(old_service, old_username) {
+        Some(_) => "least recently used",
+        None => "never used by pwvlt",
+    };
+    println!(
+        "Moving {} on {} ({}) out of slot {}.",
+        old_username, old_service, last_used, lru
+    );
+    let target = prompt_backend(pv, Some(backend_id));
+    let target_slot = prompt_slot(pv, target)?;
+    pv.copy_password(backend_id, target, target_slot, old_service, old_username)?;
+    pv.set_password(backend_id, lru, service, username, password)?;
+    record_use(service, username);
+    Ok(())
+}
+
+/// Lists the slots of a backend and asks which one to write to.
+fn prompt_slot(pv: &PasswordVault, backend_id: usize) -> Result<usize, Error> {
+    let slots = pv.backends()[backend_id].slots()?;
     print_slots(&slots)?;
-    let slot = looping_prompt("slot", slots.len() - 1);
-    Ok(pv.set_password(backend_id, slot, service, username, password)?)
+    Ok(looping_prompt("slot", slots.len() - 1))
 }
 
-fn prompt_backend(pv: &PasswordVault) -> usize {
+/// Asks which backend to use, leaving out `except`.
+fn prompt_backend(pv: &PasswordVault, except: Option<usize>) -> usize {
     println!("Available password backends:");
     let mut table = Table::new();
     table.add_row(row!["#", "Backend"]);
-    let backends = pv.backends();
-    for (i, backend) in backends.iter().enumerate() {
-        table.add_row(row!(i.to_string(), backend.name()));
+    let backends: Vec<_> = (0..pv.backends().len())
+        .filter(|&i| Some(i) != except)
+        .collect();
+    for (i, &backend) in backends.iter().enumerate() {
+        table.add_row(row!(i.to_string(), pv.backends()[backend].name()));
     }
     table.printstd();
-    looping_prompt("backend", backends.len() - 1)
+    backends[looping_prompt("backend", backends.len() - 1)]
+}
+
+/// Returns the Nitrokey user pin. It is read from `PWVLT_NITROKEY_PIN` or from
+/// the file at `PWVLT_NITROKEY_PIN_FILE` when possible, otherwise the user is
+/// prompted for it.
+fn nitrokey_password(request: PinRequest) -> Result<Pin, PwvltError> {
+    let env_pin = env::var("PWVLT_NITROKEY_PIN").ok();
+    let pin_file = env::var("PWVLT_NITROKEY_PIN_FILE").ok();
+    if request.allow_automatic {
+        if let Some(pin) = env_pin {
+            return Ok(Pin::automatic(pin));
+        }
+        if let Some(pin_file) = pin_file {
+            let pin = read_to_string(pin_file)?;
+            return Ok(Pin::automatic(pin.trim_end_matches('\n').into()));
+        }
+    } else if env_pin.is_some() || pin_file.is_some() {
+        log::warn!("Ignoring the configured Nitrokey pin, it is safer to type it in.");
+    }
+    let message = match request.serial {
+        Some(serial) => format!(
+            "Nitrokey {} user pin ({} retries left):",
+            serial, request.retries
+        ),
+        None => format!("Nitrokey user pin ({} retries left):", request.retries),
+    };
+    Ok(Pin::prompted(prompt_password_stdout(&message)?))
+}
+
+/// Returns the master password of the KeePass database. It is read from
+/// `PWVLT_KDBX_PASSWORD` or from the file at `PWVLT_KDBX_PASSWORD_FILE` when
+/// possible, otherwise the user is prompted for it.
+fn kdbx_password(path: &Path) -> Result<String, PwvltError> {
+    if let Ok(password) = env::var("PWVLT_KDBX_PASSWORD") {
+        return Ok(password);
+    }
+    if let Ok(password_file) = env::var("PWVLT_KDBX_PASSWORD_FILE") {
+        let password = read_to_string(password_file)?;
+        return Ok(password.trim_end_matches('\n').into());
+    }
+    let message = format!("Master password of {}:", path.display());
+    Ok(prompt_password_stdout(&message)?)
+}
+
+fn prompt_admin_pin(nk: &NitrokeyBackend) -> Result<String, Error> {
+    let message = format!(
+        "{} admin pin ({} retries left):",
+        nk.name(),
+        nk.admin_retry_count()?
+    );
+    Ok(prompt_password_stdout(&message)?)
+}
+
+/// Connects to the Nitrokey picked by `--serial`. Without it, there must be
+/// a single Nitrokey connected, so that pins aren't sent to the wrong one.
+fn connect_nitrokey(args: &ArgMatches) -> Result<NitrokeyBackend, Error> {
+    let serial =
+        match args.value_of("serial") {
+            Some(serial) => Some(serial.parse::<SerialNumber>().map_err(|_| {
+                Error::General(format!("Invalid serial number: {}", serial).into())
+            })?),
+            None if NitrokeyBackend::devices()?.len() > 1 => {
+                return Err(Error::General(
+                    "Several Nitrokeys are connected, pick one with --serial.".into(),
+                ))
+            }
+            None => None,
+        };
+    Ok(NitrokeyBackend::with_serial(serial, nitrokey_password)?)
+}
+
+/// Handles the commands which manage the pins and the password safe of the
+/// Nitrokey.
+fn handle_nitrokey_admin(args: &ArgMatches) -> Result<(), Error> {
+    let nk = connect_nitrokey(args)?;
+    if args.is_present("change-pin") {
+        let message = format!(
+            "Current {} user pin ({} retries left):",
+            nk.name(),
+            nk.user_retry_count()?
+        );
+        let current = prompt_password_stdout(&message)?;
+        let new = prompt_new_pin("user")?;
+        nk.change_user_pin(&current, &new)?;
+        println!("The user pin was changed.");
+    } else if args.is_present("unblock-pin") {
+        let admin_pin = prompt_admin_pin(&nk)?;
+        let new = prompt_new_pin("user")?;
+        nk.unblock_user_pin(&admin_pin, &new)?;
+        println!("The user pin was unblocked.");
+    } else if args.is_present("init-safe") {
+        if !confirm("This erases all the slots of the password safe. Continue?") {
+            return Ok(());
+        }
+        let admin_pin = prompt_admin_pin(&nk)?;
+        nk.init_password_safe(&admin_pin)?;
+        nk.unlock_safe()?;
+        println!("The password safe was initialized.");
+    }
+    Ok(())
 }
 
-fn nitrokey_password() -> Result<String, PwvltError> {
-    Ok(prompt_password_stdout("Nitrokey user pin:")?)
+/// Reports the state of every configured Nitrokey.
+fn handle_device_info(config: &pwvlt::Config) -> Result<(), Error> {
+    for serial in config.nitrokey_serials() {
+        let nk = match NitrokeyBackend::with_serial(serial, nitrokey_password) {
+            Ok(nk) => nk,
+            Err(err) => {
+                error!("{}", err);
+                continue;
+            }
+        };
+        let report = nk.device_info()?;
+        // the password safe can't be read once the user pin is locked
+        let password_safe = match nk.password_safe_usage() {
+            Ok(usage) => usage.to_string(),
+            Err(err) => format!("unavailable ({})", err),
+        };
+        print_device_report(nk.name(), &report, &password_safe);
+    }
+    Ok(())
 }
 
 fn create_vault_user_and_password<'a>(
     config: pwvlt::Config,
     values: &'a mut Values,
 ) -> (PasswordVault, &'a str, String) {
-    let pv = PasswordVault::new(config, Some(nitrokey_password));
+    let pv = PasswordVault::new(config, Some(nitrokey_password), Some(kdbx_password));
     let service = values.next().unwrap();
     let username = match pv.default(&service) {
         Some(username) => {
@@ -84,7 +352,8 @@ fn create_vault_user_and_password<'a>(
 }
 
 fn handle_args(args: ArgMatches) -> Result<(), Error> {
-    let mut config = config::load_config()?;
+    let config_path = config::config_path(args.value_of("config"))?;
+    let (config, origins) = config::load_config(&config_path)?;
     if let Some(mut values) = args.values_of("get") {
         let (pv, service, username) = create_vault_user_and_password(config, &mut values);
         handle_get(pv, service, &username)
@@ -105,8 +374,30 @@ fn handle_args(args: ArgMatches) -> Result<(), Error> {
     } else if let Some(mut values) = args.values_of("set-default") {
         let service = values.next().unwrap();
         let username = values.next().unwrap();
-        config.default.insert(service.into(), username.into());
-        write_config(&config)
+        set_default(&config_path, service, username)
+    } else if let Some(service) = args.value_of("unset-default") {
+        if !unset_default(&config_path, service)? {
+            log::warn!("No default username was set for {}.", service);
+        }
+        Ok(())
+    } else if args.is_present("show-config") {
+        print_config(&origins);
+        Ok(())
+    } else if args.is_present("list-defaults") {
+        print_defaults(&config.default);
+        Ok(())
+    } else if args.is_present("device-info") {
+        handle_device_info(&config)
+    } else if args.is_present("list-nitrokeys") {
+        print_devices(&NitrokeyBackend::devices()?);
+        Ok(())
+    } else if args.is_present("change-pin")
+        || args.is_present("unblock-pin")
+        || args.is_present("init-safe")
+    {
+        handle_nitrokey_admin(&args)
+    } else if args.is_present("generate") {
+        handle_generate(config, &args)
     } else {
         Ok(())
     }
@@ -118,18 +409,53 @@ fn handle_backend_errors(err: PwvltError) {
             "An error occurred while accessing the Keyring backend: {}",
             e
         ),
+        PwvltError::Dbus(e) => error!("A D-Bus error occurred: {}", e),
+        PwvltError::KWallet(e) => error!(
+            "An error occurred while accessing the KWallet backend: {}",
+            e
+        ),
+        PwvltError::Pass(e) => error!("An error occurred while accessing the pass backend: {}", e),
+        PwvltError::KdbxOpen(_) | PwvltError::KdbxSave(_) | PwvltError::Kdbx(_) => {
+            error!("{}", err)
+        }
         PwvltError::Io(e) => error!("An internal IO error occurred: {}", e),
         PwvltError::Nitrokey(e) => error!(
             "An error occurred while accessing the Nitrokey backend: {}",
             e
         ),
+        PwvltError::NitrokeyNotFound { .. } => error!("{}", err),
         PwvltError::PasswordGeneration(e) => error!(
             "An error occurred while generating a random password: {}",
             e
         ),
         PwvltError::PasswordNotFound => error!("No password could be found!"),
-        PwvltError::Skip => unimplemented!("SkipError"),
+        PwvltError::NotFoundAnywhere { attempts } => {
+            error!("No password could be found!");
+            for Attempt { backend, error } in attempts {
+                error!("{}: {}", backend, error);
+            }
+        }
+        PwvltError::Backend { backend, source } => {
+            error!("The {} backend failed.", backend);
+            handle_backend_errors(*source);
+        }
+        PwvltError::Timeout { .. } => error!("{}", err),
+        PwvltError::UserPinLocked => {
+            error!("The Nitrokey user pin is locked! Use --unblock-pin to set a new one.")
+        }
+        PwvltError::AdminPinLocked => {
+            error!("The Nitrokey admin pin is locked! The device must be factory reset.")
+        }
+        PwvltError::AutomaticPinRefused { retries } => error!(
+            "Refusing to use an automatic Nitrokey pin with {} retries left.",
+            retries
+        ),
+        PwvltError::PinCancelled => error!("No Nitrokey pin was entered."),
+        PwvltError::FieldTooLong { .. }
+        | PwvltError::InvalidCharacter { .. }
+        | PwvltError::ReadOnlySlot { .. } => error!("{}", err),
         PwvltError::Utf8(_) => error!("Failed to parse a password as Utf8"),
+        PwvltError::Config(e) => error!("Invalid password policy: {}", e),
     }
 }
 
@@ -137,8 +463,17 @@ fn main() {
     let matches = App::new("Password Vault")
         .version("1.0")
         .author("Robert B. <bartlensky.robert@gmail.com>")
-        .about("Stores passwords on the local keyring or on a Nitrokey.")
+        .about(
+            "Stores passwords on the local keyring, in KWallet, in a pass store, in a KeePass database or on a Nitrokey.",
+        )
         .arg(Arg::with_name("v").short("v").multiple(true))
+        .arg(
+            Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .help("Use the config file at <path> instead of the default one.")
+                .value_names(&["path"]),
+        )
         .arg(
             Arg::with_name("get")
                 .short("g")
@@ -160,11 +495,103 @@ fn main() {
                 .help("Set the default <username> for <service>.")
                 .value_names(&["service", "username"]),
         )
-        .group(
-            ArgGroup::with_name("cmd")
-                .required(true)
-                .args(&["set", "get", "set-default"]),
+        .arg(
+            Arg::with_name("unset-default")
+                .short("u")
+                .long("unset-default")
+                .help("Remove the default username of <service>.")
+                .value_names(&["service"]),
+        )
+        .arg(
+            Arg::with_name("show-config")
+                .long("show-config")
+                .help("Print the effective config and where each value was set."),
+        )
+        .arg(
+            Arg::with_name("list-defaults")
+                .short("l")
+                .long("list-defaults")
+                .help("List the default usernames of all services."),
+        )
+        .arg(
+            Arg::with_name("list-nitrokeys")
+                .long("list-nitrokeys")
+                .help("List the connected Nitrokeys and their serial numbers."),
+        )
+        .arg(
+            Arg::with_name("device-info")
+                .long("device-info")
+                .help("Report the state of the configured Nitrokeys (asks for the user pin)."),
+        )
+        .arg(
+            Arg::with_name("serial")
+                .long("serial")
+                .help("The serial number of the Nitrokey to manage.")
+                .value_names(&["serial"]),
+        )
+        .arg(
+            Arg::with_name("change-pin")
+                .long("change-pin")
+                .help("Change the user pin of the Nitrokey."),
+        )
+        .arg(
+            Arg::with_name("unblock-pin")
+                .long("unblock-pin")
+                .help("Set a new user pin for the Nitrokey using the admin pin."),
+        )
+        .arg(
+            Arg::with_name("init-safe")
+                .long("init-safe")
+                .help("Initialize the password safe of a new Nitrokey (erases all its slots)."),
+        )
+        .arg(
+            Arg::with_name("generate")
+                .short("G")
+                .long("generate")
+                .help("Generate [count] random passwords without storing them.")
+                .value_names(&["count"])
+                .min_values(0)
+                .max_values(1),
+        )
+        .arg(
+            Arg::with_name("copy")
+                .long("copy")
+                .requires("generate")
+                .help("Copy the generated password to the clipboard instead of printing it."),
+        )
+        .arg(
+            Arg::with_name("length")
+                .long("length")
+                .requires("generate")
+                .help("Override the configured password length.")
+                .value_names(&["length"]),
+        )
+        .args(
+            &POLICY_OVERRIDES
+                .iter()
+                .map(|(name, help)| {
+                    Arg::with_name(name)
+                        .long(name)
+                        .requires("generate")
+                        .help(help)
+                        .possible_values(&["true", "false"])
+                })
+                .collect::<Vec<_>>(),
         )
+        .group(ArgGroup::with_name("cmd").required(true).args(&[
+            "set",
+            "get",
+            "set-default",
+            "unset-default",
+            "list-defaults",
+            "show-config",
+            "list-nitrokeys",
+            "device-info",
+            "change-pin",
+            "unblock-pin",
+            "init-safe",
+            "generate",
+        ]))
         .get_matches();
 
     let level = match matches.occurrences_of("v") {
@@ -182,8 +609,13 @@ fn main() {
                 e
             ),
             Error::TomlDeserialize(e) => error!("Failed to deserialize config file: {}", e),
-            Error::TomlSerialize(e) => error!("Failed to serialize config file: {}", e),
+            Error::TomlSerialize(e) => error!("Failed to serialize config: {}", e),
+            Error::TomlEdit(e) => error!("Failed to edit config file: {}", e),
+            Error::Env { var, value } => {
+                error!("Invalid value {:?} for environment variable {}", value, var)
+            }
             Error::Pwvlt(e) => handle_backend_errors(e),
+            Error::Config(e) => error!("Invalid config file: {}", e),
             Error::General(e) => error!("An internal error occured: {}", e),
         }
     }
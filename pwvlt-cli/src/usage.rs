@@ -0,0 +1,169 @@
+use std::env;
+use std::fs::{create_dir_all, read_to_string};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use pwvlt::Slot;
+use toml::value::Table;
+use toml::Value;
+
+use crate::config::replace_file;
+use crate::error::Error;
+
+/// Records when each username of each service was last used, so that the
+/// least recently used entry can be moved off a full backend. It is stored
+/// as a table of services, each mapping usernames to a UNIX timestamp.
+pub struct Usage {
+    path: PathBuf,
+    services: Table,
+}
+
+/// Returns `$XDG_STATE_HOME/pwvlt/usage.toml`, or
+/// `~/.local/state/pwvlt/usage.toml`.
+fn usage_path() -> Result<PathBuf, Error> {
+    // relative paths in XDG_STATE_HOME must be ignored
+    let state_home = match env::var_os("XDG_STATE_HOME").map(PathBuf::from) {
+        Some(dir) if dir.is_absolute() => dir,
+        _ => home::home_dir()
+            .ok_or(Error::HomeNotFound)?
+            .join(".local")
+            .join("state"),
+    };
+    Ok(state_home.join("pwvlt").join("usage.toml"))
+}
+
+impl Usage {
+    pub fn load() -> Result<Usage, Error> {
+        let path = usage_path()?;
+        let services = if path.exists() {
+            match read_to_string(&path)?.parse::<Value>()? {
+                Value::Table(services) => services,
+                _ => Table::new(),
+            }
+        } else {
+            Table::new()
+        };
+        Ok(Usage { path, services })
+    }
+
+    /// Like `load`, but an unreadable usage file is treated as empty (every
+    /// entry was never used), with a warning.
+    pub fn load_or_empty() -> Usage {
+        Usage::load().unwrap_or_else(|err| {
+            log::warn!(
+                "Failed to read the usage file, treating every entry as never used: {}",
+                err
+            );
+            Usage {
+                path: usage_path().unwrap_or_default(),
+                services: Table::new(),
+            }
+        })
+    }
+
+    /// Returns when `username` was last used on `service`, if pwvlt ever
+    /// used it.
+    pub fn last_used(&self, service: &str, username: &str) -> Option<i64> {
+        self.services.get(service)?.get(username)?.as_integer()
+    }
+
+    /// Returns the index of the least recently used slot. Slots which were
+    /// never used come first.
+    pub fn least_recently_used(&self, slots: &[Slot]) -> Option<usize> {
+        (0..slots.len()).min_by_key(|&i| self.last_used(&slots[i].service, &slots[i].username))
+    }
+
+    /// Marks `username` on `service` as used now, and saves the usage file.
+    pub fn record(&mut self, service: &str, username: &str) -> Result<(), Error> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| Error::General(Box::new(e)))?;
+        let usernames = self
+            .services
+            .entry(service.to_string())
+            .or_insert_with(|| Value::Table(Table::new()));
+        if let Value::Table(usernames) = usernames {
+            usernames.insert(username.into(), Value::Integer(now.as_secs() as i64));
+        }
+        if let Some(dir) = self.path.parent().filter(|d| !d.exists()) {
+            create_dir_all(dir)?;
+        }
+        let contents = Value::Table(self.services.clone()).to_string();
+        replace_file(&self.path, &contents)
+    }
+}
+
+/// Records that `username` on `service` was used. Failing to do so doesn't
+/// fail the command.
+pub fn record_use(service: &str, username: &str) {
+    if let Err(err) = Usage::load().and_then(|mut usage| usage.record(service, username)) {
+        log::warn!(
+            "Failed to record the use of {} on {}: {}",
+            username,
+            service,
+            err
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(services: &str) -> Usage {
+        Usage {
+            path: PathBuf::new(),
+            services: toml::from_str(services).unwrap(),
+        }
+    }
+
+    fn slots(names: &[(&str, &str)]) -> Vec<Slot> {
+        names
+            .iter()
+            .map(|&(service, username)| Slot {
+                service: service.into(),
+                username: username.into(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn never_used_entries_come_first() {
+        let usage = usage("[github]\nalice = 300\n[mail]\nbob = 100\n");
+        let slots = slots(&[
+            ("github", "alice"),
+            ("mail", "bob"),
+            ("gitlab", "carol"),
+            ("github", "dave"),
+        ]);
+
+        // the first of the never used entries
+        assert_eq!(usage.least_recently_used(&slots), Some(2));
+    }
+
+    #[test]
+    fn the_oldest_entry_comes_next() {
+        let usage = usage("[github]\nalice = 300\ndave = 200\n[mail]\nbob = 100\n");
+        let slots = slots(&[("github", "alice"), ("mail", "bob"), ("github", "dave")]);
+
+        assert_eq!(usage.least_recently_used(&slots), Some(1));
+        assert_eq!(usage.least_recently_used(&[]), None);
+    }
+
+    #[test]
+    fn record_replaces_the_usage_file() {
+        let dir = env::temp_dir().join(format!("pwvlt-usage-{}", std::process::id()));
+        let mut usage = Usage {
+            path: dir.join("pwvlt").join("usage.toml"),
+            services: Table::new(),
+        };
+        usage.record("github", "alice").unwrap();
+        usage.record("mail", "bob").unwrap();
+
+        let saved: Table = toml::from_str(&read_to_string(&usage.path).unwrap()).unwrap();
+        assert!(saved["github"].get("alice").is_some());
+        assert!(saved["mail"].get("bob").is_some());
+        assert!(!dir.join("pwvlt").join("usage.toml.tmp").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
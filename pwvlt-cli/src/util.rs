@@ -1,7 +1,11 @@
 use prettytable::{cell, row, Table};
+use rpassword::prompt_password_stdout;
 
-use pwvlt::{PwvltError, Slot};
+use pwvlt::{DeviceInfo, DeviceReport, PwvltError, Slot};
 
+use crate::config::Origins;
+
+use std::collections::HashMap;
 use std::fmt::Display;
 use std::io::{self, stdout, BufRead, Write};
 use std::ops::Sub;
@@ -22,6 +26,59 @@ pub fn print_slots(slots: &[Slot]) -> Result<(), PwvltError> {
     Ok(())
 }
 
+pub fn print_devices(devices: &[DeviceInfo]) {
+    let mut table = Table::new();
+    table.add_row(row!["Model", "Serial", "Path"]);
+    for device in devices {
+        let model = match device.model {
+            Some(model) => model.to_string(),
+            None => "unsupported".into(),
+        };
+        let serial = match device.serial_number {
+            Some(serial) => serial.to_string(),
+            None => "unknown".into(),
+        };
+        table.add_row(row![model, serial, device.path]);
+    }
+    table.printstd();
+}
+
+/// Prints the state of a Nitrokey. `password_safe` is the slot usage of its
+/// password safe, or why it couldn't be read.
+pub fn print_device_report(name: &str, report: &DeviceReport, password_safe: &str) {
+    println!("{}:", name);
+    let mut table = Table::new();
+    table.add_row(row!["Model", report.model]);
+    table.add_row(row!["Firmware", report.firmware]);
+    table.add_row(row!["Serial", report.serial]);
+    table.add_row(row!["User pin retries", report.user_retries]);
+    table.add_row(row!["Admin pin retries", report.admin_retries]);
+    table.add_row(row!["Password safe", password_safe]);
+    table.add_row(row!["HOTP slots", report.hotp_slots]);
+    table.add_row(row!["TOTP slots", report.totp_slots]);
+    table.printstd();
+}
+
+pub fn print_defaults(defaults: &HashMap<String, String>) {
+    let mut defaults: Vec<_> = defaults.iter().collect();
+    defaults.sort();
+    let mut table = Table::new();
+    table.add_row(row!["Service", "Username"]);
+    for (service, username) in defaults {
+        table.add_row(row![service, username]);
+    }
+    table.printstd();
+}
+
+pub fn print_config(origins: &Origins) {
+    let mut table = Table::new();
+    table.add_row(row!["Key", "Value", "Source"]);
+    for (key, (value, origin)) in origins {
+        table.add_row(row![key, value, origin]);
+    }
+    table.printstd();
+}
+
 pub fn looping_prompt<T>(item: &str, max_val: T) -> T
 where
     T: Ord + Sub + Display + FromStr,
@@ -47,3 +104,24 @@ pub fn prompt_string<S: AsRef<str>>(message: S) -> String {
     stdin.lock().read_line(&mut item_val).unwrap();
     item_val.trim().into()
 }
+
+/// Asks a yes/no question, defaulting to no.
+pub fn confirm<S: AsRef<str>>(question: S) -> bool {
+    let answer = prompt_string(format!("{} [y/N]", question.as_ref()));
+    answer.eq_ignore_ascii_case("y") || answer.eq_ignore_ascii_case("yes")
+}
+
+/// Prompts for a new pin until it is entered the same way twice.
+pub fn prompt_new_pin(kind: &str) -> io::Result<String> {
+    loop {
+        let pin = prompt_password_stdout(&format!("New {} pin:", kind))?;
+        if pin.is_empty() {
+            println!("The pin can't be empty.");
+            continue;
+        }
+        if prompt_password_stdout(&format!("Repeat the new {} pin:", kind))? == pin {
+            return Ok(pin);
+        }
+        println!("The pins don't match.");
+    }
+}
@@ -0,0 +1,48 @@
+//! Compares the first lookup on a Nitrokey, which builds the slot index, with
+//! the following ones, which use it. Run with
+//! `cargo bench -p pwvlt --features simulator`.
+
+use pwvlt::{Backend, NitrokeySimulator, Pin};
+
+use std::time::{Duration, Instant};
+
+/// About the time a command takes over USB.
+const LATENCY: Duration = Duration::from_millis(2);
+const LOOKUPS: u32 = 20;
+
+fn main() {
+    let simulator = NitrokeySimulator::new();
+    let serial = simulator.add_device("0x00000001");
+    // half the slots are programmed
+    for slot in (0..16).step_by(2) {
+        let service = format!("service{}", slot);
+        simulator.write_slot(serial, slot, &service, "user", "password");
+    }
+    simulator.set_latency(LATENCY);
+    let nk = simulator
+        .backend(Some(serial), |_| {
+            Ok(Pin::prompted(NitrokeySimulator::USER_PIN.into()))
+        })
+        .unwrap();
+    nk.unlock_safe().unwrap();
+
+    let commands = simulator.commands();
+    let start = Instant::now();
+    nk.password("service14", "user").unwrap();
+    println!(
+        "first lookup:  {:>10.2?} ({} commands)",
+        start.elapsed(),
+        simulator.commands() - commands
+    );
+
+    let commands = simulator.commands();
+    let start = Instant::now();
+    for _ in 0..LOOKUPS {
+        nk.password("service14", "user").unwrap();
+    }
+    println!(
+        "cached lookup: {:>10.2?} ({} commands)",
+        start.elapsed() / LOOKUPS,
+        (simulator.commands() - commands) / LOOKUPS as usize
+    );
+}
@@ -0,0 +1,59 @@
+use crate::{PasswordVault, PwvltError, Slot};
+
+use tokio::task::{spawn_blocking, JoinError};
+
+use std::panic::resume_unwind;
+use std::sync::Arc;
+
+/// Wraps a `PasswordVault` so that it can be used from async code. Backends
+/// talk to D-Bus and USB devices synchronously, so every operation is run on
+/// tokio's blocking thread pool.
+#[derive(Clone)]
+pub struct AsyncPasswordVault {
+    vault: Arc<PasswordVault>,
+}
+
+/// Propagates panics that happened inside a blocking task.
+fn join<T>(res: Result<T, JoinError>) -> T {
+    res.unwrap_or_else(|err| resume_unwind(err.into_panic()))
+}
+
+impl AsyncPasswordVault {
+    pub fn new(vault: Arc<PasswordVault>) -> AsyncPasswordVault {
+        AsyncPasswordVault { vault }
+    }
+
+    pub fn vault(&self) -> &Arc<PasswordVault> {
+        &self.vault
+    }
+
+    pub async fn password(&self, service: &str, username: &str) -> Result<String, PwvltError> {
+        let vault = Arc::clone(&self.vault);
+        let (service, username) = (service.to_string(), username.to_string());
+        join(spawn_blocking(move || vault.password(&service, &username)).await)
+    }
+
+    pub async fn set_password(
+        &self,
+        backend: usize,
+        slot: usize,
+        service: &str,
+        username: &str,
+        password: Option<&str>,
+    ) -> Result<(), PwvltError> {
+        let vault = Arc::clone(&self.vault);
+        let (service, username) = (service.to_string(), username.to_string());
+        let password = password.map(str::to_string);
+        join(
+            spawn_blocking(move || {
+                vault.set_password(backend, slot, &service, &username, password.as_deref())
+            })
+            .await,
+        )
+    }
+
+    pub async fn slots(&self, backend: usize) -> Result<Vec<Slot>, PwvltError> {
+        let vault = Arc::clone(&self.vault);
+        join(spawn_blocking(move || vault.backends()[backend].slots()).await)
+    }
+}
@@ -1,4 +1,4 @@
-use crate::PwvltError;
+use crate::{PwvltError, SlotUsage};
 
 #[derive(Clone)]
 pub struct Slot {
@@ -15,7 +15,9 @@ impl Default for Slot {
     }
 }
 
-pub trait Backend {
+/// Backends can be shared between threads, so implementations must take care
+/// of their own synchronization.
+pub trait Backend: Send + Sync {
     fn password(&self, service: &str, username: &str) -> Result<String, PwvltError>;
 
     fn set_password(
@@ -26,9 +28,22 @@ pub trait Backend {
         password: &str,
     ) -> Result<(), PwvltError>;
 
-    fn log_error(&self, err: PwvltError);
+    fn name(&self) -> &str;
 
-    fn name(&self) -> &'static str;
+    /// The longest password (in bytes) the backend can store, if it is
+    /// limited.
+    fn max_password_length(&self) -> Option<usize> {
+        None
+    }
 
+    /// Lists the slots `set_password` can write to. The order must not change
+    /// between calls unless a slot is written, since callers refer to slots
+    /// by their index; writing to a programmed slot replaces what it held.
     fn slots(&self) -> Result<Vec<Slot>, PwvltError>;
+
+    /// How many slots are programmed, for backends which have a fixed number
+    /// of them.
+    fn slot_usage(&self) -> Result<Option<SlotUsage>, PwvltError> {
+        Ok(None)
+    }
 }
@@ -1,6 +1,13 @@
+use crate::util::matches_pattern;
+use crate::ConfigError;
+
+use nitrokey::SerialNumber;
 use serde::{Deserialize, Serialize};
 
 use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
 
 /// Represents a user's configuration.
 #[derive(Default, Deserialize, Serialize)]
@@ -11,22 +18,371 @@ pub struct Config {
     /// A mapping from services to usernames. Users can set default
     /// usernames for specific services.
     pub default: HashMap<String, String>,
+    #[serde(default)]
+    /// A mapping from backend names (e.g. `keyring`) to their timeouts.
+    pub timeouts: HashMap<String, Timeouts>,
+    #[serde(default)]
+    /// Rules which pick the backend a service is written to, and which
+    /// backends are queried first when reading it.
+    pub routes: Vec<Route>,
+    #[serde(default)]
+    /// The Nitrokeys to use, in lookup order. If none are listed, the first
+    /// Nitrokey found is used.
+    pub nitrokey: Vec<NitrokeyDevice>,
+    #[serde(default)]
+    pub keyring: Keyring,
+    #[serde(default)]
+    pub pass: Pass,
+    #[serde(default)]
+    pub kdbx: Kdbx,
 }
 
-#[derive(Deserialize, Serialize)]
+impl Config {
+    /// Checks that the configuration is consistent, returning the first
+    /// problem found.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        self.general.validate()?;
+        self.password.validate()?;
+        for backend in self.timeouts.keys() {
+            if backend.parse::<BackendName>().is_err() {
+                return Err(ConfigError::UnknownBackend {
+                    key: format!("timeouts.{}", backend),
+                });
+            }
+        }
+        if !self.nitrokey.is_empty() && !self.general.backends.contains(&BackendName::Nitrokey) {
+            return Err(ConfigError::UnloadedBackend {
+                key: "nitrokey".into(),
+                backend: BackendName::Nitrokey.to_string(),
+            });
+        }
+        if self.general.backends.contains(&BackendName::Kdbx) {
+            self.kdbx.validate()?;
+        }
+        let mut serials = Vec::with_capacity(self.nitrokey.len());
+        for (i, device) in self.nitrokey.iter().enumerate() {
+            let key = format!("nitrokey[{}].serial", i);
+            let serial = parse_serial(&key, &device.serial)?;
+            if serials.contains(&serial) {
+                return Err(ConfigError::DuplicateBackend {
+                    key,
+                    backend: format!("nitrokey {}", serial),
+                });
+            }
+            serials.push(serial);
+        }
+        for (i, route) in self.routes.iter().enumerate() {
+            if !self.general.backends.contains(&route.backend) {
+                return Err(ConfigError::UnloadedBackend {
+                    key: format!("routes[{}].backend", i),
+                    backend: route.backend.to_string(),
+                });
+            }
+            if let Some(serial) = &route.serial {
+                let key = format!("routes[{}].serial", i);
+                if route.backend != BackendName::Nitrokey {
+                    return Err(ConfigError::InvalidSerial {
+                        key,
+                        serial: serial.clone(),
+                    });
+                }
+                let serial = parse_serial(&key, serial)?;
+                if !serials.contains(&serial) {
+                    return Err(ConfigError::UnknownSerial {
+                        key,
+                        serial: serial.to_string(),
+                    });
+                }
+            }
+        }
+        for (service, username) in &self.default {
+            if service.is_empty() || username.is_empty() {
+                return Err(ConfigError::EmptyDefault {
+                    key: format!("default.{:?}", service),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the rules which match `service`, in the configured order.
+    pub fn routes(&self, service: &str) -> Vec<&Route> {
+        self.routes
+            .iter()
+            .filter(|route| matches_pattern(&route.pattern, service))
+            .collect()
+    }
+
+    /// Returns the serial numbers of the configured Nitrokeys, or `None` if
+    /// the first Nitrokey found should be used.
+    pub fn nitrokey_serials(&self) -> Vec<Option<SerialNumber>> {
+        if self.nitrokey.is_empty() {
+            return vec![None];
+        }
+        self.nitrokey
+            .iter()
+            .map(|device| device.serial.parse().ok())
+            .collect()
+    }
+
+    /// Returns the timeouts of the given backend.
+    pub fn timeouts(&self, backend: BackendName) -> Timeouts {
+        self.timeouts
+            .get(&backend.to_string())
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum BackendName {
     Nitrokey,
     Keyring,
+    #[serde(rename = "kwallet")]
+    KWallet,
+    Pass,
+    Kdbx,
+}
+
+impl fmt::Display for BackendName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            BackendName::Nitrokey => "nitrokey",
+            BackendName::Keyring => "keyring",
+            BackendName::KWallet => "kwallet",
+            BackendName::Pass => "pass",
+            BackendName::Kdbx => "kdbx",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for BackendName {
+    type Err = ();
+
+    fn from_str(name: &str) -> Result<BackendName, ()> {
+        match name {
+            "nitrokey" => Ok(BackendName::Nitrokey),
+            "keyring" => Ok(BackendName::Keyring),
+            "kwallet" => Ok(BackendName::KWallet),
+            "pass" => Ok(BackendName::Pass),
+            "kdbx" => Ok(BackendName::Kdbx),
+            _ => Err(()),
+        }
+    }
+}
+
+fn parse_serial(key: &str, serial: &str) -> Result<SerialNumber, ConfigError> {
+    serial.parse().map_err(|_| ConfigError::InvalidSerial {
+        key: key.into(),
+        serial: serial.into(),
+    })
+}
+
+/// Routes the services that match `pattern` to `backend`. Patterns can use
+/// `*` to match any sequence of characters (e.g. `*.corp.example`).
+#[derive(Deserialize, Serialize)]
+pub struct Route {
+    pub pattern: String,
+    pub backend: BackendName,
+    /// Picks one of the configured Nitrokeys. Without it, the route matches
+    /// all of them.
+    #[serde(default)]
+    pub serial: Option<String>,
+}
+
+impl Route {
+    /// Whether the route targets the given backend.
+    pub fn matches(&self, backend: BackendName, serial: Option<SerialNumber>) -> bool {
+        match &self.serial {
+            Some(routed) => backend == self.backend && routed.parse().ok() == serial,
+            None => backend == self.backend,
+        }
+    }
+}
+
+/// A Nitrokey, selected by its serial number (e.g. `0x0001b2c3`).
+#[derive(Deserialize, Serialize)]
+pub struct NitrokeyDevice {
+    pub serial: String,
+}
+
+/// Settings of the keyring backend.
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct Keyring {
+    /// The label of the Secret Service collection to use, which is created if
+    /// it doesn't exist. The default collection is used if it isn't set.
+    #[serde(default)]
+    pub collection: Option<String>,
+    /// Whether `slots` also lists the items of other applications. They can
+    /// be read, but not overwritten.
+    #[serde(default)]
+    pub include_foreign: bool,
+    /// Whether the collection is locked again once pwvlt is done with it.
+    /// Collections which were already unlocked are left alone.
+    #[serde(default)]
+    pub relock: Relock,
+    /// Also lock the collection after this many seconds without being used,
+    /// unless `relock` is `never`. This is meant for long-running embedders.
+    #[serde(default)]
+    pub relock_idle: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Relock {
+    /// Leave the collection unlocked.
+    #[default]
+    Never,
+    /// Lock the collection when the backend is dropped.
+    OnDrop,
+}
+
+fn default_gpg() -> String {
+    "gpg".into()
+}
+
+/// Settings of the pass backend.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Pass {
+    /// The password store. Defaults to `$PASSWORD_STORE_DIR`, or
+    /// `~/.password-store`.
+    #[serde(default)]
+    pub store: Option<PathBuf>,
+    /// The gpg binary which encrypts and decrypts the entries.
+    #[serde(default = "default_gpg")]
+    pub gpg: String,
+}
+
+impl Default for Pass {
+    fn default() -> Pass {
+        Pass {
+            store: None,
+            gpg: default_gpg(),
+        }
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Settings of the KeePass backend.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Kdbx {
+    /// The KDBX 4 database. It must be set to use the backend.
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    /// A key file which unlocks the database, on its own or along with the
+    /// master password.
+    #[serde(default)]
+    pub key_file: Option<PathBuf>,
+    /// Whether the database has a master password, which the unlock hook is
+    /// asked for.
+    #[serde(default = "default_true")]
+    pub password: bool,
+}
+
+impl Default for Kdbx {
+    fn default() -> Kdbx {
+        Kdbx {
+            path: None,
+            key_file: None,
+            password: true,
+        }
+    }
+}
+
+impl Kdbx {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.path.is_none() {
+            return Err(ConfigError::MissingKey {
+                key: "kdbx.path".into(),
+            });
+        }
+        // a database can't be opened without any key
+        if !self.password && self.key_file.is_none() {
+            return Err(ConfigError::MissingKey {
+                key: "kdbx.key_file".into(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// How many seconds pwvlt waits for a backend to connect, unless configured
+/// otherwise.
+const DEFAULT_CONNECT_TIMEOUT: u64 = 10;
+
+fn default_connect_timeout() -> Option<u64> {
+    Some(DEFAULT_CONNECT_TIMEOUT)
+}
+
+#[derive(Clone, Copy, Deserialize, Serialize)]
+pub struct Timeouts {
+    /// Seconds to wait for the backend to connect.
+    #[serde(default = "default_connect_timeout")]
+    pub connect: Option<u64>,
+    /// Seconds to wait for a single operation. Note that this includes the
+    /// time spent in prompts (e.g. entering the Nitrokey pin). There is no
+    /// limit by default.
+    #[serde(default)]
+    pub operation: Option<u64>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Timeouts {
+        Timeouts {
+            connect: default_connect_timeout(),
+            operation: None,
+        }
+    }
 }
 
 #[derive(Default, Deserialize, Serialize)]
 pub struct General {
     /// The backends that pwvlt will load.
     pub backends: Vec<BackendName>,
+    #[serde(default)]
+    pub lookup: LookupStrategy,
 }
 
-#[derive(Default, Deserialize, Serialize)]
+/// How `PasswordVault::password` queries the backends.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LookupStrategy {
+    /// Query the backends one by one, in the configured order.
+    #[default]
+    Sequential,
+    /// Query all backends at the same time, returning the first password
+    /// found.
+    ParallelFirstHit,
+    /// Query all backends at the same time and report the backends which hold
+    /// different passwords for the same service and username.
+    ParallelAll,
+}
+
+impl General {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.backends.is_empty() {
+            return Err(ConfigError::NoBackends {
+                key: "general.backends".into(),
+            });
+        }
+        for (i, backend) in self.backends.iter().enumerate() {
+            if self.backends[..i].contains(backend) {
+                return Err(ConfigError::DuplicateBackend {
+                    key: format!("general.backends[{}]", i),
+                    backend: backend.to_string(),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Default, Deserialize, Serialize)]
 /// The fields of this struct are already described here:
 /// https://docs.rs/passwords/1.1.5/passwords/struct.PasswordGenerator.html
 pub struct Password {
@@ -37,3 +393,241 @@ pub struct Password {
     pub symbols: bool,
     pub strict: bool,
 }
+
+impl Password {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let classes = [
+            self.numbers,
+            self.lowercase_letters,
+            self.uppercase_letters,
+            self.symbols,
+        ]
+        .iter()
+        .filter(|enabled| **enabled)
+        .count();
+        if classes == 0 {
+            return Err(ConfigError::EmptyAlphabet {
+                key: "password".into(),
+            });
+        }
+        // in strict mode every enabled character class must be used at least
+        // once.
+        let min = if self.strict { classes } else { 1 };
+        if self.length < min {
+            return Err(ConfigError::PasswordLength {
+                key: "password.length".into(),
+                length: self.length,
+                min,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A valid configuration using the given backends.
+    fn config(backends: &[BackendName]) -> Config {
+        Config {
+            general: General {
+                backends: backends.to_vec(),
+                lookup: Default::default(),
+            },
+            password: Password {
+                length: 16,
+                numbers: true,
+                lowercase_letters: true,
+                uppercase_letters: true,
+                symbols: false,
+                strict: true,
+            },
+            ..Default::default()
+        }
+    }
+
+    fn nitrokey(serial: &str) -> NitrokeyDevice {
+        NitrokeyDevice {
+            serial: serial.into(),
+        }
+    }
+
+    fn route(backend: BackendName, serial: Option<&str>) -> Route {
+        Route {
+            pattern: "*.example".into(),
+            backend,
+            serial: serial.map(String::from),
+        }
+    }
+
+    #[test]
+    fn valid_config() {
+        let mut config = config(&[BackendName::Nitrokey, BackendName::Keyring]);
+        config.nitrokey = vec![nitrokey("0x1"), nitrokey("0x2")];
+        config.routes = vec![route(BackendName::Nitrokey, Some("0x2"))];
+        config.default.insert("example.com".into(), "alice".into());
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn empty_alphabet() {
+        let mut config = config(&[BackendName::Keyring]);
+        config.password.numbers = false;
+        config.password.lowercase_letters = false;
+        config.password.uppercase_letters = false;
+        match config.validate() {
+            Err(ConfigError::EmptyAlphabet { key }) => assert_eq!(key, "password"),
+            res => panic!("Expected EmptyAlphabet, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn strict_length_below_the_number_of_classes() {
+        let mut config = config(&[BackendName::Keyring]);
+        config.password.length = 2;
+        match config.validate() {
+            Err(ConfigError::PasswordLength { key, length, min }) => {
+                assert_eq!(key, "password.length");
+                assert_eq!((length, min), (2, 3));
+            }
+            res => panic!("Expected PasswordLength, got {:?}", res),
+        }
+        // without strict mode a single character is enough
+        config.password.strict = false;
+        assert!(config.password.validate().is_ok());
+    }
+
+    #[test]
+    fn no_backends() {
+        match config(&[]).validate() {
+            Err(ConfigError::NoBackends { key }) => assert_eq!(key, "general.backends"),
+            res => panic!("Expected NoBackends, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn duplicate_backends() {
+        let config = config(&[
+            BackendName::Keyring,
+            BackendName::Pass,
+            BackendName::Keyring,
+        ]);
+        match config.validate() {
+            Err(ConfigError::DuplicateBackend { key, backend }) => {
+                assert_eq!(key, "general.backends[2]");
+                assert_eq!(backend, "keyring");
+            }
+            res => panic!("Expected DuplicateBackend, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn duplicate_nitrokeys() {
+        let mut config = config(&[BackendName::Nitrokey]);
+        config.nitrokey = vec![nitrokey("0x1"), nitrokey("0x00000001")];
+        match config.validate() {
+            Err(ConfigError::DuplicateBackend { key, .. }) => {
+                assert_eq!(key, "nitrokey[1].serial")
+            }
+            res => panic!("Expected DuplicateBackend, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn unknown_backend_timeouts() {
+        let mut config = config(&[BackendName::Keyring]);
+        config.timeouts.insert("floppy".into(), Timeouts::default());
+        match config.validate() {
+            Err(ConfigError::UnknownBackend { key }) => assert_eq!(key, "timeouts.floppy"),
+            res => panic!("Expected UnknownBackend, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn unloaded_backends() {
+        let mut config = config(&[BackendName::Keyring]);
+        config.nitrokey = vec![nitrokey("0x1")];
+        match config.validate() {
+            Err(ConfigError::UnloadedBackend { key, backend }) => {
+                assert_eq!(key, "nitrokey");
+                assert_eq!(backend, "nitrokey");
+            }
+            res => panic!("Expected UnloadedBackend, got {:?}", res),
+        }
+
+        config.nitrokey.clear();
+        config.routes = vec![
+            route(BackendName::Keyring, None),
+            route(BackendName::Pass, None),
+        ];
+        match config.validate() {
+            Err(ConfigError::UnloadedBackend { key, backend }) => {
+                assert_eq!(key, "routes[1].backend");
+                assert_eq!(backend, "pass");
+            }
+            res => panic!("Expected UnloadedBackend, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn empty_default() {
+        let mut config = config(&[BackendName::Keyring]);
+        config.default.insert("example.com".into(), "".into());
+        match config.validate() {
+            Err(ConfigError::EmptyDefault { key }) => {
+                assert_eq!(key, "default.\"example.com\"")
+            }
+            res => panic!("Expected EmptyDefault, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn missing_kdbx_keys() {
+        let mut config = config(&[BackendName::Kdbx]);
+        match config.validate() {
+            Err(ConfigError::MissingKey { key }) => assert_eq!(key, "kdbx.path"),
+            res => panic!("Expected MissingKey, got {:?}", res),
+        }
+        config.kdbx.path = Some("passwords.kdbx".into());
+        config.kdbx.password = false;
+        match config.validate() {
+            Err(ConfigError::MissingKey { key }) => assert_eq!(key, "kdbx.key_file"),
+            res => panic!("Expected MissingKey, got {:?}", res),
+        }
+        config.kdbx.key_file = Some("passwords.key".into());
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn invalid_serials() {
+        let mut config = config(&[BackendName::Nitrokey, BackendName::Keyring]);
+        config.nitrokey = vec![nitrokey("0x1"), nitrokey("banana")];
+        match config.validate() {
+            Err(ConfigError::InvalidSerial { key, serial }) => {
+                assert_eq!(key, "nitrokey[1].serial");
+                assert_eq!(serial, "banana");
+            }
+            res => panic!("Expected InvalidSerial, got {:?}", res),
+        }
+
+        // only Nitrokey routes can pick a serial number
+        config.nitrokey.pop();
+        config.routes = vec![route(BackendName::Keyring, Some("0x1"))];
+        match config.validate() {
+            Err(ConfigError::InvalidSerial { key, .. }) => assert_eq!(key, "routes[0].serial"),
+            res => panic!("Expected InvalidSerial, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn unknown_serial() {
+        let mut config = config(&[BackendName::Nitrokey]);
+        config.nitrokey = vec![nitrokey("0x1")];
+        config.routes = vec![route(BackendName::Nitrokey, Some("0x2"))];
+        match config.validate() {
+            Err(ConfigError::UnknownSerial { key, .. }) => assert_eq!(key, "routes[0].serial"),
+            res => panic!("Expected UnknownSerial, got {:?}", res),
+        }
+    }
+}
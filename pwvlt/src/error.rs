@@ -1,20 +1,85 @@
-use nitrokey::CommandError;
 use secret_service::SsError;
 
+use std::error::Error;
 use std::fmt;
 
 #[derive(derive_more::From, Debug)]
 pub enum PwvltError {
     /// Usually returned by the `password` method.
     PasswordNotFound,
+    /// Returned by `PasswordVault::password` when none of the backends have
+    /// the password. It records why each backend failed.
+    #[from(ignore)]
+    NotFoundAnywhere {
+        attempts: Vec<Attempt>,
+    },
+    /// An error that occurred in a specific backend.
+    #[from(ignore)]
+    Backend {
+        backend: String,
+        source: Box<PwvltError>,
+    },
     Keyring(SsError),
+    Dbus(dbus::Error),
+    /// kwalletd isn't available, or it failed to carry out a request.
+    #[from(ignore)]
+    KWallet(String),
+    /// The password store is missing, or gpg failed to encrypt or decrypt an
+    /// entry.
+    #[from(ignore)]
+    Pass(String),
+    KdbxOpen(keepass::error::DatabaseOpenError),
+    KdbxSave(keepass::error::DatabaseSaveError),
+    /// The KeePass database isn't configured, or can't be used.
+    #[from(ignore)]
+    Kdbx(String),
     Io(std::io::Error),
-    Nitrokey(CommandError),
-    /// A skip error is returned by `NitrokeyBackend::new`, when the admin pin
-    /// is needed to unlock the Nitrokey.
-    Skip,
+    Nitrokey(nitrokey::Error),
+    /// None of the connected Nitrokeys has the configured serial number.
+    #[from(ignore)]
+    NitrokeyNotFound {
+        serial: String,
+    },
+    /// The Nitrokey user pin is locked, so the admin pin is needed to unblock
+    /// it.
+    UserPinLocked,
+    /// The Nitrokey admin pin is locked, the device must be factory reset.
+    AdminPinLocked,
+    /// The unlock hook returned an automatic pin when only one retry was left.
+    #[from(ignore)]
+    AutomaticPinRefused {
+        retries: u8,
+    },
+    /// A value is longer than what the backend can store.
+    #[from(ignore)]
+    FieldTooLong {
+        field: &'static str,
+        length: usize,
+        max: usize,
+    },
+    /// A value contains a character the backend can't store.
+    #[from(ignore)]
+    InvalidCharacter {
+        field: &'static str,
+        character: char,
+    },
+    /// The slot holds an item of another application.
+    #[from(ignore)]
+    ReadOnlySlot {
+        slot: usize,
+    },
+    /// The user didn't enter a pin.
+    PinCancelled,
     PasswordGeneration(String),
+    /// A backend took longer than its configured timeout.
+    #[from(ignore)]
+    Timeout {
+        backend: String,
+        operation: &'static str,
+        seconds: u64,
+    },
     Utf8(std::string::FromUtf8Error),
+    Config(ConfigError),
 }
 
 impl fmt::Display for PwvltError {
@@ -22,12 +87,180 @@ impl fmt::Display for PwvltError {
         let message = match self {
             PwvltError::Utf8(err) => format!("{}", err),
             PwvltError::PasswordNotFound => "Password not found.".to_string(),
+            PwvltError::NotFoundAnywhere { attempts } if attempts.is_empty() => {
+                "Password not found, no backends are available.".to_string()
+            }
+            PwvltError::NotFoundAnywhere { attempts } => {
+                let attempts: Vec<_> = attempts
+                    .iter()
+                    .map(|Attempt { backend, error }| format!("{}: {}", backend, error))
+                    .collect();
+                format!(
+                    "Password not found in any backend ({}).",
+                    attempts.join("; ")
+                )
+            }
+            PwvltError::Backend { backend, source } => format!("{} backend: {}", backend, source),
             PwvltError::Keyring(err) => format!("Keyring error: {}", err),
+            PwvltError::Dbus(err) => format!("D-Bus error: {}", err),
+            PwvltError::KWallet(err) => format!("KWallet error: {}", err),
+            PwvltError::Pass(err) => format!("pass error: {}", err),
+            PwvltError::KdbxOpen(err) => format!("Failed to open the KeePass database: {}", err),
+            PwvltError::KdbxSave(err) => format!("Failed to save the KeePass database: {}", err),
+            PwvltError::Kdbx(err) => format!("KeePass error: {}", err),
             PwvltError::Io(err) => format!("I/O error: {}", err),
             PwvltError::Nitrokey(err) => format!("Nitrokey error: {}", err),
-            PwvltError::Skip => "Skip error".to_string(),
+            PwvltError::NitrokeyNotFound { serial } => {
+                format!("No Nitrokey with serial number {} is connected.", serial)
+            }
+            PwvltError::UserPinLocked => {
+                "The Nitrokey user pin is locked, use the admin pin to unblock it.".to_string()
+            }
+            PwvltError::AdminPinLocked => {
+                "The Nitrokey admin pin is locked, the device must be factory reset.".to_string()
+            }
+            PwvltError::AutomaticPinRefused { retries } => format!(
+                "Refusing to use an automatic pin with {} retries left.",
+                retries
+            ),
+            PwvltError::FieldTooLong { field, length, max } => format!(
+                "The {} is {} bytes long, but at most {} bytes can be stored.",
+                field, length, max
+            ),
+            PwvltError::InvalidCharacter { field, character } => {
+                format!("The {} can't contain {:?}.", field, character)
+            }
+            PwvltError::ReadOnlySlot { slot } => format!(
+                "Slot {} belongs to another application and can't be overwritten.",
+                slot
+            ),
+            PwvltError::PinCancelled => "No pin was entered.".to_string(),
             PwvltError::PasswordGeneration(err) => format!("Error generating password: {}", err),
+            PwvltError::Config(err) => format!("Invalid configuration: {}", err),
+            PwvltError::Timeout {
+                backend,
+                operation,
+                seconds,
+            } => format!(
+                "{} timed out after {}s while {}.",
+                backend, seconds, operation
+            ),
         };
         write!(f, "{}", message)
     }
 }
+
+impl Error for PwvltError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PwvltError::Backend { source, .. } => Some(source.as_ref()),
+            PwvltError::Keyring(err) => Some(err),
+            PwvltError::Dbus(err) => Some(err),
+            PwvltError::KdbxOpen(err) => Some(err),
+            PwvltError::KdbxSave(err) => Some(err),
+            PwvltError::Io(err) => Some(err),
+            PwvltError::Nitrokey(err) => Some(err),
+            PwvltError::Utf8(err) => Some(err),
+            PwvltError::Config(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// The reason why a backend failed to provide a password.
+#[derive(Debug)]
+pub struct Attempt {
+    /// The name of the backend.
+    pub backend: String,
+    pub error: PwvltError,
+}
+
+/// Returned by `Config::validate`. Each variant records the path of the
+/// offending key (e.g. `password.length`).
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The password length is too short for the configured policy.
+    PasswordLength {
+        key: String,
+        length: usize,
+        min: usize,
+    },
+    /// None of the character classes are enabled.
+    EmptyAlphabet {
+        key: String,
+    },
+    NoBackends {
+        key: String,
+    },
+    DuplicateBackend {
+        key: String,
+        backend: String,
+    },
+    UnknownBackend {
+        key: String,
+    },
+    /// The backend isn't listed in `general.backends`.
+    UnloadedBackend {
+        key: String,
+        backend: String,
+    },
+    /// A default username (or the service it belongs to) is empty.
+    EmptyDefault {
+        key: String,
+    },
+    /// A setting which the loaded backends need isn't set.
+    MissingKey {
+        key: String,
+    },
+    /// The Nitrokey serial number isn't a hexadecimal number.
+    InvalidSerial {
+        key: String,
+        serial: String,
+    },
+    /// The Nitrokey serial number isn't listed in `nitrokey`.
+    UnknownSerial {
+        key: String,
+        serial: String,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::PasswordLength { key, length, min } => write!(
+                f,
+                "{}: length {} is too short, the password policy needs at least {}",
+                key, length, min
+            ),
+            ConfigError::EmptyAlphabet { key } => {
+                write!(f, "{}: at least one character class must be enabled", key)
+            }
+            ConfigError::NoBackends { key } => {
+                write!(f, "{}: at least one backend must be configured", key)
+            }
+            ConfigError::DuplicateBackend { key, backend } => {
+                write!(f, "{}: backend {} is listed more than once", key, backend)
+            }
+            ConfigError::UnknownBackend { key } => write!(f, "{}: unknown backend", key),
+            ConfigError::UnloadedBackend { key, backend } => write!(
+                f,
+                "{}: backend {} is not listed in general.backends",
+                key, backend
+            ),
+            ConfigError::EmptyDefault { key } => {
+                write!(f, "{}: service and username must not be empty", key)
+            }
+            ConfigError::MissingKey { key } => write!(f, "{}: must be set", key),
+            ConfigError::InvalidSerial { key, serial } => {
+                write!(f, "{}: invalid Nitrokey serial number {:?}", key, serial)
+            }
+            ConfigError::UnknownSerial { key, serial } => write!(
+                f,
+                "{}: no Nitrokey with serial number {} is configured",
+                key, serial
+            ),
+        }
+    }
+}
+
+impl Error for ConfigError {}
@@ -0,0 +1,120 @@
+use crate::PwvltError;
+
+use nitrokey::{CommandError, CommunicationError};
+
+/// Events emitted by a `PasswordVault` while it manages its backends. The
+/// `backend` fields hold the name of the backend (see `Backend::name`).
+#[derive(Debug)]
+pub enum Event<'a> {
+    BackendLoaded {
+        backend: &'a str,
+    },
+    /// The backend couldn't be loaded, so it won't be used.
+    BackendFailed {
+        backend: &'a str,
+        error: &'a PwvltError,
+    },
+    LookupStarted {
+        backend: &'a str,
+        service: &'a str,
+        username: &'a str,
+    },
+    PasswordFound {
+        backend: &'a str,
+        service: &'a str,
+        username: &'a str,
+    },
+    /// The backend doesn't have the password, or failed to look it up.
+    LookupMiss {
+        backend: &'a str,
+        service: &'a str,
+        username: &'a str,
+        error: &'a PwvltError,
+    },
+    /// The backends hold different passwords for the same service and
+    /// username.
+    LookupConflict {
+        service: &'a str,
+        username: &'a str,
+        backends: &'a [&'a str],
+    },
+    /// The backend is about to ask for a pin (e.g. to unlock a Nitrokey).
+    PinRequested {
+        backend: &'a str,
+        /// How many wrong pins can still be entered.
+        retries: u8,
+    },
+    PasswordWritten {
+        backend: &'a str,
+        service: &'a str,
+        username: &'a str,
+    },
+}
+
+/// Receives the events of a `PasswordVault`, so that embedders can render
+/// them in their own UI, metrics or audit logs.
+pub trait Observer: Send + Sync {
+    fn notify(&self, event: &Event);
+}
+
+/// The default observer, which reports events using the `log` crate.
+#[derive(Default)]
+pub struct LogObserver;
+
+impl Observer for LogObserver {
+    fn notify(&self, event: &Event) {
+        match event {
+            Event::BackendLoaded { backend } => {
+                log::info!("{} backend loaded successfully!", backend)
+            }
+            Event::BackendFailed { backend, error } => {
+                log::warn!("Failed to access {}: {}", backend, error)
+            }
+            Event::LookupStarted { backend, .. } => {
+                log::info!("Looking for password in {}.", backend)
+            }
+            Event::PasswordFound { backend, .. } => log::info!("Found password in {}.", backend),
+            Event::LookupMiss { backend, error, .. } => {
+                let message = match error {
+                    PwvltError::PasswordNotFound => format!("Password not found in {}!", backend),
+                    PwvltError::UserPinLocked => {
+                        format!("Skipping {} search, the user pin is locked...", backend)
+                    }
+                    PwvltError::Nitrokey(nitrokey::Error::CommunicationError(
+                        CommunicationError::NotConnected,
+                    )) => format!("Couldn't connect to {}!", backend),
+                    PwvltError::Nitrokey(nitrokey::Error::CommandError(
+                        CommandError::WrongPassword,
+                    )) => "User pin was incorrect.".into(),
+                    err => err.to_string(),
+                };
+                log::warn!("{}", message);
+            }
+            Event::LookupConflict {
+                service,
+                username,
+                backends,
+            } => log::warn!(
+                "Backends {} hold different passwords for {} on {}.",
+                backends.join(", "),
+                username,
+                service
+            ),
+            Event::PinRequested { backend, retries } => log::info!(
+                "Prompting for the {} pin ({} retries left).",
+                backend,
+                retries
+            ),
+            Event::PasswordWritten {
+                backend,
+                service,
+                username,
+            } => log::info!(
+                "Password for {} on {} written to {}.",
+                username,
+                service,
+                backend
+            ),
+        }
+    }
+}
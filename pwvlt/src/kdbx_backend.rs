@@ -0,0 +1,575 @@
+use crate::config::Kdbx;
+use crate::{Backend, PwvltError, Slot};
+
+use keepass::config::DatabaseVersion;
+use keepass::db::{DeletedObject, Entry, Group, Meta, Node, Times, Value};
+use keepass::{Database, DatabaseKey};
+use secstr::SecStr;
+use zeroize::Zeroizing;
+
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Returns the master password of the database at the given path, e.g. by
+/// prompting the user.
+pub type KdbxUnlock = fn(&Path) -> Result<String, PwvltError>;
+
+type UnlockHook = Box<dyn Fn(&Path) -> Result<String, PwvltError> + Send + Sync>;
+
+/// Stores passwords in a KeePass database (KDBX 4). Each group below the
+/// root is a service, named by its path (e.g. `Team/Servers`), and each of
+/// its entries is a username. Entries are matched by their `UserName` field,
+/// or by their title when it is empty. The recycle bin is left out.
+pub struct KdbxBackend {
+    path: PathBuf,
+    key_file: Option<PathBuf>,
+    has_password: bool,
+    unlock_hook: UnlockHook,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    password: Option<Zeroizing<String>>,
+    /// The database as it was last read or written. It is read again when
+    /// the file changes, e.g. when someone else saves it on a network share.
+    loaded: Option<Loaded>,
+}
+
+struct Loaded {
+    database: Database,
+    modified: SystemTime,
+}
+
+fn is_recycle_bin(meta: &Meta, group: &Group) -> bool {
+    meta.recyclebin_uuid == Some(group.uuid)
+}
+
+/// Returns the username of an entry, or its title if it has no username.
+fn entry_username(entry: &Entry) -> Option<&str> {
+    entry
+        .get_username()
+        .filter(|username| !username.is_empty())
+        .or_else(|| entry.get_title())
+}
+
+fn find_entry<'a>(group: &'a mut Group, username: &str) -> Option<&'a mut Entry> {
+    group.children.iter_mut().find_map(|node| match node {
+        Node::Entry(entry) if entry_username(entry) == Some(username) => Some(entry),
+        _ => None,
+    })
+}
+
+/// Returns the group of `service`. With `create`, the missing groups along
+/// the way are added.
+fn find_group<'a>(
+    mut group: &'a mut Group,
+    meta: &Meta,
+    service: &str,
+    create: bool,
+) -> Option<&'a mut Group> {
+    for name in service.split('/') {
+        let position = group.children.iter().position(|node| match node {
+            Node::Group(child) => child.name == name && !is_recycle_bin(meta, child),
+            Node::Entry(_) => false,
+        });
+        let i = match position {
+            Some(i) => i,
+            None if create => {
+                group.add_child(Group::new(name));
+                group.children.len() - 1
+            }
+            None => return None,
+        };
+        group = match &mut group.children[i] {
+            Node::Group(child) => child,
+            Node::Entry(_) => unreachable!(),
+        };
+    }
+    Some(group)
+}
+
+/// Lists the entries below `group`, whose path is `service`.
+fn collect_slots(group: &Group, meta: &Meta, service: &str, slots: &mut Vec<Slot>) {
+    for node in &group.children {
+        match node {
+            Node::Group(child) if !is_recycle_bin(meta, child) => {
+                let service = if service.is_empty() {
+                    child.name.clone()
+                } else {
+                    format!("{}/{}", service, child.name)
+                };
+                collect_slots(child, meta, &service, slots);
+            }
+            // entries of the root group have no service
+            Node::Entry(entry) if !service.is_empty() => {
+                if let Some(username) = entry_username(entry) {
+                    slots.push(Slot {
+                        service: service.into(),
+                        username: username.into(),
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Returns the entries of the database, sorted by service and username.
+fn sorted_slots(database: &Database) -> Vec<Slot> {
+    let mut slots = Vec::new();
+    collect_slots(&database.root, &database.meta, "", &mut slots);
+    slots.sort_by(|a, b| (&a.service, &a.username).cmp(&(&b.service, &b.username)));
+    slots
+}
+
+fn check_service(service: &str) -> Result<(), PwvltError> {
+    if service.split('/').any(str::is_empty) {
+        return Err(PwvltError::Kdbx(format!("Invalid service {:?}.", service)));
+    }
+    Ok(())
+}
+
+impl KdbxBackend {
+    /// Uses the configured database. If it has a master password,
+    /// `unlock_hook` is called to get it the first time the database is read,
+    /// and again after a wrong password.
+    pub fn new<F>(config: Kdbx, unlock_hook: F) -> Result<KdbxBackend, PwvltError>
+    where
+        F: Fn(&Path) -> Result<String, PwvltError> + Send + Sync + 'static,
+    {
+        let path = config
+            .path
+            .ok_or_else(|| PwvltError::Kdbx("No database is configured.".into()))?;
+        if !path.is_file() {
+            return Err(PwvltError::Kdbx(format!(
+                "{} doesn't exist.",
+                path.display()
+            )));
+        }
+        Ok(KdbxBackend {
+            path,
+            key_file: config.key_file,
+            has_password: config.password,
+            unlock_hook: Box::new(unlock_hook),
+            state: Default::default(),
+        })
+    }
+
+    fn key(&self, state: &mut State) -> Result<DatabaseKey, PwvltError> {
+        let mut key = DatabaseKey::new();
+        if self.has_password {
+            let password = match state.password.take() {
+                Some(password) => password,
+                None => Zeroizing::new((self.unlock_hook)(&self.path)?),
+            };
+            key = key.with_password(&password);
+            state.password = Some(password);
+        }
+        if let Some(key_file) = &self.key_file {
+            key = key.with_keyfile(&mut File::open(key_file)?)?;
+        }
+        Ok(key)
+    }
+
+    fn modified(&self) -> Result<SystemTime, PwvltError> {
+        Ok(fs::metadata(&self.path)?.modified()?)
+    }
+
+    fn load(&self, state: &mut State) -> Result<Loaded, PwvltError> {
+        let modified = self.modified()?;
+        let key = self.key(state)?;
+        let database = match Database::open(&mut File::open(&self.path)?, key) {
+            Ok(database) => database,
+            Err(err) => {
+                // ask for the password again next time, it may be wrong
+                state.password = None;
+                return Err(err.into());
+            }
+        };
+        // older versions can be read, but not written back
+        if !matches!(database.config.version, DatabaseVersion::KDB4(_)) {
+            return Err(PwvltError::Kdbx(format!(
+                "{} isn't a KDBX 4 database, save it with a recent KeePass first.",
+                self.path.display()
+            )));
+        }
+        Ok(Loaded { database, modified })
+    }
+
+    /// Calls `f` with the database, reading it first if the file changed.
+    fn with_database<T, F>(&self, f: F) -> Result<T, PwvltError>
+    where
+        F: FnOnce(&mut Database) -> Result<T, PwvltError>,
+    {
+        let mut state = self.state.lock().unwrap();
+        let modified = self.modified()?;
+        match &mut state.loaded {
+            Some(loaded) if loaded.modified == modified => f(&mut loaded.database),
+            _ => {
+                if state.loaded.is_some() {
+                    log::info!("{} changed, reading it again.", self.path.display());
+                }
+                let mut loaded = self.load(&mut state)?;
+                let res = f(&mut loaded.database);
+                state.loaded = Some(loaded);
+                res
+            }
+        }
+    }
+
+    /// Writes the database next to the file and renames it over the file, so
+    /// a failure leaves the previous version intact.
+    fn save(&self, state: &mut State, database: &Database) -> Result<SystemTime, PwvltError> {
+        let mut name = self.path.file_name().unwrap_or_default().to_os_string();
+        name.push(".tmp");
+        let tmp = self.path.with_file_name(name);
+        let result = File::create(&tmp)
+            .map_err(PwvltError::from)
+            .and_then(|mut file| {
+                fs::set_permissions(&tmp, fs::metadata(&self.path)?.permissions())?;
+                database.save(&mut file, self.key(state)?)?;
+                Ok(file.sync_all()?)
+            });
+        if let Err(err) = result {
+            let _ = fs::remove_file(&tmp);
+            return Err(err);
+        }
+        fs::rename(&tmp, &self.path)?;
+        self.modified()
+    }
+}
+
+impl Backend for KdbxBackend {
+    fn password(&self, service: &str, username: &str) -> Result<String, PwvltError> {
+        check_service(service)?;
+        self.with_database(|database| {
+            let Database { root, meta, .. } = database;
+            let entry = find_group(root, meta, service, false)
+                .and_then(|group| find_entry(group, username))
+                .ok_or(PwvltError::PasswordNotFound)?;
+            Ok(entry.get_password().unwrap_or_default().into())
+        })
+    }
+
+    fn set_password(
+        &self,
+        slot: usize,
+        service: &str,
+        username: &str,
+        password: &str,
+    ) -> Result<(), PwvltError> {
+        check_service(service)?;
+        let mut state = self.state.lock().unwrap();
+        // always start from the file, so changes saved by others aren't lost
+        let Loaded { mut database, .. } = self.load(&mut state)?;
+        let old = sorted_slots(&database).into_iter().nth(slot);
+        let Database {
+            root,
+            meta,
+            deleted_objects,
+            ..
+        } = &mut database;
+
+        let group = find_group(root, meta, service, true).unwrap();
+        let password = Value::Protected(SecStr::from(password));
+        match find_entry(group, username) {
+            Some(entry) => {
+                // keep the previous password in the history of the entry
+                entry.update_history();
+                entry.fields.insert("Password".into(), password);
+                entry.update_history();
+            }
+            None => {
+                let mut entry = Entry::new();
+                entry
+                    .fields
+                    .insert("Title".into(), Value::Unprotected(username.into()));
+                entry
+                    .fields
+                    .insert("UserName".into(), Value::Unprotected(username.into()));
+                entry.fields.insert("Password".into(), password);
+                group.add_child(entry);
+            }
+        }
+
+        if let Some(old) = old.filter(|old| old.service != service || old.username != username) {
+            if let Some(group) = find_group(root, meta, &old.service, false) {
+                let position = group.children.iter().position(|node| match node {
+                    Node::Entry(entry) => entry_username(entry) == Some(old.username.as_str()),
+                    Node::Group(_) => false,
+                });
+                if let Some(Node::Entry(entry)) = position.map(|i| group.children.remove(i)) {
+                    deleted_objects.objects.push(DeletedObject {
+                        uuid: entry.uuid,
+                        deletion_time: Times::now(),
+                    });
+                }
+            }
+        }
+
+        let modified = self.save(&mut state, &database)?;
+        state.loaded = Some(Loaded { database, modified });
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        "KeePass"
+    }
+
+    fn slots(&self) -> Result<Vec<Slot>, PwvltError> {
+        self.with_database(|database| {
+            let mut slots = sorted_slots(database);
+            slots.push(Default::default());
+            Ok(slots)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use keepass::config::{DatabaseConfig, KdfConfig};
+
+    use crate::temp_dir::TempDir;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    const PASSWORD: &str = "master";
+
+    /// A database in a temporary folder, removed when dropped.
+    struct TempDatabase {
+        dir: TempDir,
+        path: PathBuf,
+    }
+
+    fn entry(username: &str, password: &str) -> Entry {
+        let mut entry = Entry::new();
+        entry
+            .fields
+            .insert("Title".into(), Value::Unprotected(username.into()));
+        entry
+            .fields
+            .insert("UserName".into(), Value::Unprotected(username.into()));
+        entry
+            .fields
+            .insert("Password".into(), Value::Protected(SecStr::from(password)));
+        entry
+    }
+
+    fn group(name: &str, entries: Vec<Entry>) -> Group {
+        let mut group = Group::new(name);
+        for entry in entries {
+            group.add_child(entry);
+        }
+        group
+    }
+
+    impl TempDatabase {
+        /// Saves a database holding `groups` below its root.
+        fn new(groups: Vec<Group>) -> TempDatabase {
+            let dir = TempDir::new("pwvlt-kdbx");
+            let path = dir.path().join("passwords.kdbx");
+            let database = TempDatabase { dir, path };
+            let mut db = Database::new(DatabaseConfig {
+                // keep the tests fast
+                kdf_config: KdfConfig::Aes { rounds: 1 },
+                ..Default::default()
+            });
+            for group in groups {
+                db.root.add_child(group);
+            }
+            database.save(&db);
+            database
+        }
+
+        fn key() -> DatabaseKey {
+            DatabaseKey::new().with_password(PASSWORD)
+        }
+
+        fn open(&self) -> Database {
+            Database::open(&mut File::open(&self.path).unwrap(), TempDatabase::key()).unwrap()
+        }
+
+        fn save(&self, database: &Database) {
+            database
+                .save(&mut File::create(&self.path).unwrap(), TempDatabase::key())
+                .unwrap();
+        }
+
+        fn backend(&self) -> KdbxBackend {
+            self.backend_with(|_| Ok(PASSWORD.into()))
+        }
+
+        fn backend_with<F>(&self, unlock_hook: F) -> KdbxBackend
+        where
+            F: Fn(&Path) -> Result<String, PwvltError> + Send + Sync + 'static,
+        {
+            let config = Kdbx {
+                path: Some(self.path.clone()),
+                ..Default::default()
+            };
+            KdbxBackend::new(config, unlock_hook).unwrap()
+        }
+    }
+
+    fn slot_names(backend: &KdbxBackend) -> Vec<(String, String)> {
+        let mut slots = backend.slots().unwrap();
+        // the new slot
+        slots.pop();
+        slots
+            .into_iter()
+            .map(|slot| (slot.service, slot.username))
+            .collect()
+    }
+
+    #[test]
+    fn passwords_are_read_and_written() {
+        let team = group("Servers", vec![entry("alice", "first")]);
+        let mut parent = group("Team", Vec::new());
+        parent.add_child(team);
+        let database = TempDatabase::new(vec![parent]);
+        let backend = database.backend();
+
+        assert_eq!(backend.password("Team/Servers", "alice").unwrap(), "first");
+        let new_slot = backend.slots().unwrap().len() - 1;
+        backend
+            .set_password(new_slot, "Mail", "bob", "second")
+            .unwrap();
+        // slots are sorted, so alice's entry moved to slot 1
+        backend
+            .set_password(1, "Team/Servers", "alice", "changed")
+            .unwrap();
+
+        // the changes are in the file
+        let backend = database.backend();
+        assert_eq!(
+            slot_names(&backend),
+            [
+                ("Mail".to_string(), "bob".to_string()),
+                ("Team/Servers".to_string(), "alice".to_string()),
+            ]
+        );
+        assert_eq!(backend.password("Mail", "bob").unwrap(), "second");
+        assert_eq!(
+            backend.password("Team/Servers", "alice").unwrap(),
+            "changed"
+        );
+        assert!(matches!(
+            backend.password("Team", "alice"),
+            Err(PwvltError::PasswordNotFound)
+        ));
+    }
+
+    #[test]
+    fn writing_to_a_slot_replaces_its_entry() {
+        let database = TempDatabase::new(vec![group(
+            "GitHub",
+            vec![entry("alice", "old"), entry("bob", "kept")],
+        )]);
+        let old_uuid = match &database.open().root.children[0] {
+            Node::Group(group) => match &group.children[0] {
+                Node::Entry(entry) => entry.uuid,
+                Node::Group(_) => unreachable!(),
+            },
+            Node::Entry(_) => unreachable!(),
+        };
+        let backend = database.backend();
+
+        // slot 0 is alice's entry
+        backend.set_password(0, "GitLab", "carol", "new").unwrap();
+        assert_eq!(
+            slot_names(&backend),
+            [
+                ("GitHub".to_string(), "bob".to_string()),
+                ("GitLab".to_string(), "carol".to_string()),
+            ]
+        );
+        let saved = database.open();
+        assert!(saved
+            .deleted_objects
+            .objects
+            .iter()
+            .any(|deleted| deleted.uuid == old_uuid));
+        assert!(matches!(
+            database.backend().password("GitHub", "alice"),
+            Err(PwvltError::PasswordNotFound)
+        ));
+    }
+
+    #[test]
+    fn a_failed_save_leaves_the_database_intact() {
+        let database = TempDatabase::new(vec![group("GitHub", vec![entry("alice", "old")])]);
+        let before = fs::read(&database.path).unwrap();
+        // the temporary file can't be created
+        fs::create_dir(database.dir.path().join("passwords.kdbx.tmp")).unwrap();
+        let backend = database.backend();
+
+        assert!(backend.set_password(0, "GitHub", "alice", "new").is_err());
+        assert_eq!(fs::read(&database.path).unwrap(), before);
+        assert_eq!(backend.password("GitHub", "alice").unwrap(), "old");
+    }
+
+    #[test]
+    fn the_recycle_bin_is_skipped() {
+        let bin = group("Recycle Bin", vec![entry("alice", "deleted")]);
+        let bin_uuid = bin.uuid;
+        let database = TempDatabase::new(vec![bin, group("GitHub", vec![entry("bob", "kept")])]);
+        let mut db = database.open();
+        db.meta.recyclebin_uuid = Some(bin_uuid);
+        database.save(&db);
+        let backend = database.backend();
+
+        assert_eq!(
+            slot_names(&backend),
+            [("GitHub".to_string(), "bob".to_string())]
+        );
+        assert!(matches!(
+            backend.password("Recycle Bin", "alice"),
+            Err(PwvltError::PasswordNotFound)
+        ));
+    }
+
+    #[test]
+    fn a_wrong_password_is_asked_again() {
+        let database = TempDatabase::new(vec![group("GitHub", vec![entry("alice", "secret")])]);
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&calls);
+        let backend = database.backend_with(move |_| {
+            Ok(match counter.fetch_add(1, Ordering::SeqCst) {
+                0 => "wrong".into(),
+                _ => PASSWORD.into(),
+            })
+        });
+
+        assert!(matches!(
+            backend.password("GitHub", "alice"),
+            Err(PwvltError::KdbxOpen(_))
+        ));
+        assert_eq!(backend.password("GitHub", "alice").unwrap(), "secret");
+        assert_eq!(backend.password("GitHub", "alice").unwrap(), "secret");
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn the_database_is_read_again_when_it_changes() {
+        let database = TempDatabase::new(vec![group("GitHub", vec![entry("alice", "old")])]);
+        let backend = database.backend();
+        assert_eq!(backend.password("GitHub", "alice").unwrap(), "old");
+
+        // e.g. saved by KeePassXC
+        let mut db = database.open();
+        db.root.add_child(group("Mail", vec![entry("bob", "new")]));
+        database.save(&db);
+        let file = File::options().write(true).open(&database.path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(10))
+            .unwrap();
+
+        assert_eq!(backend.password("Mail", "bob").unwrap(), "new");
+    }
+}
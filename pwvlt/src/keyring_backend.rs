@@ -1,111 +1,259 @@
+use crate::config::{Keyring, Relock};
+use crate::vault::PromptLock;
 use crate::{Backend, PwvltError, Slot};
 
 use secret_service::{Collection, EncryptionType, SecretService};
 
-use std::cell::RefCell;
 use std::collections::HashMap;
-use std::ptr::NonNull;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
 
 const NOT_SET: &str = "not_set";
 
-pub struct KeyringBackend<'a> {
-    secret_service: NonNull<SecretService>,
-    collection: RefCell<Option<Collection<'a>>>,
-    slots: RefCell<Option<Vec<Slot>>>,
+/// Items created by pwvlt are tagged with this `application` attribute.
+const APPLICATION: &str = "pwvlt";
+
+struct Entry {
+    slot: Slot,
+    /// Whether the item was created by another application, in which case it
+    /// can't be overwritten.
+    foreign: bool,
+}
+
+/// A `SecretService` can't be shared between threads, so the backend opens a
+/// new session for every operation.
+pub struct KeyringBackend {
+    config: Keyring,
+    slots: Mutex<Option<Vec<Entry>>>,
+    /// When the collection was last used, if pwvlt unlocked it. Operations
+    /// hold this lock, so that the collection isn't locked while in use.
+    unlocked: Arc<Mutex<Option<Instant>>>,
+    /// Held while the Secret Service may prompt to create or unlock the
+    /// collection.
+    prompt: Option<PromptLock>,
 }
 
-impl<'a> Drop for KeyringBackend<'a> {
+impl Drop for KeyringBackend {
     fn drop(&mut self) {
-        // make sure we release the secret_service field.
-        unsafe { Box::from_raw(self.secret_service.as_ptr()) };
+        if self.config.relock != Relock::OnDrop {
+            return;
+        }
+        let mut unlocked = self.unlocked.lock().unwrap();
+        if unlocked.take().is_some() {
+            if let Err(err) = lock_collection(&self.config) {
+                log::warn!("Failed to lock the keyring: {}", err);
+            }
+        }
     }
 }
 
-impl<'a> KeyringBackend<'a> {
-    pub fn new() -> Result<KeyringBackend<'a>, PwvltError> {
-        let ss = Box::leak(Box::new(SecretService::new(EncryptionType::Dh)?));
+/// Returns the configured collection, creating it if needed. Creating it
+/// may show a prompt, during which `prompt` is held.
+fn find_collection<'a>(
+    secret_service: &'a SecretService,
+    config: &Keyring,
+    prompt: Option<&PromptLock>,
+) -> Result<Collection<'a>, PwvltError> {
+    let label = match &config.collection {
+        Some(label) => label,
+        None => return Ok(secret_service.get_default_collection()?),
+    };
+    for collection in secret_service.get_all_collections()? {
+        if collection.get_label()? == *label {
+            return Ok(collection);
+        }
+    }
+    log::info!("Creating the {} keyring collection.", label);
+    let _prompt = prompt.map(PromptLock::acquire);
+    Ok(secret_service.create_collection(label, "")?)
+}
+
+fn lock_collection(config: &Keyring) -> Result<(), PwvltError> {
+    let secret_service = SecretService::new(EncryptionType::Dh)?;
+    // the collection exists, since pwvlt unlocked it
+    find_collection(&secret_service, config, None)?.lock()?;
+    Ok(())
+}
+
+/// Locks the collection once it wasn't used for `idle`. The thread stops
+/// when the collection is locked or the backend is dropped.
+fn relock_when_idle(unlocked: Weak<Mutex<Option<Instant>>>, config: Keyring, idle: Duration) {
+    thread::spawn(move || {
+        let mut wait = idle;
+        loop {
+            thread::sleep(wait);
+            let unlocked = match unlocked.upgrade() {
+                Some(unlocked) => unlocked,
+                None => return,
+            };
+            let mut unlocked = unlocked.lock().unwrap();
+            let last_use = match *unlocked {
+                Some(last_use) => last_use,
+                None => return,
+            };
+            if last_use.elapsed() < idle {
+                wait = idle - last_use.elapsed();
+                continue;
+            }
+            log::info!(
+                "Locking the keyring after {}s of inactivity.",
+                idle.as_secs()
+            );
+            if let Err(err) = lock_collection(&config) {
+                log::warn!("Failed to lock the keyring: {}", err);
+            }
+            unlocked.take();
+            return;
+        }
+    });
+}
+
+impl KeyringBackend {
+    /// Uses the default collection, only listing the items created by pwvlt.
+    pub fn new() -> Result<KeyringBackend, PwvltError> {
+        KeyringBackend::with_config(Default::default())
+    }
+
+    pub fn with_config(config: Keyring) -> Result<KeyringBackend, PwvltError> {
+        // make sure the Secret Service is reachable
+        SecretService::new(EncryptionType::Dh)?;
         Ok(KeyringBackend {
-            secret_service: NonNull::from(ss),
-            collection: RefCell::new(None),
-            slots: RefCell::new(None),
+            config,
+            slots: Mutex::new(None),
+            unlocked: Arc::new(Mutex::new(None)),
+            prompt: None,
         })
     }
 
-    /// Tries to unlock the collection. It also initialises collection and
-    /// slots fields in case the underlying values are None.
-    fn unlock_collection(&self) -> Result<(), PwvltError> {
-        if self.collection.borrow().is_none() {
-            let collection = unsafe {
-                std::mem::transmute::<&SecretService, &'a SecretService>(
-                    self.secret_service.as_ref(),
-                )
-            }
-            .get_default_collection()?;
+    /// Makes the Secret Service prompts wait for the other prompts of a
+    /// vault.
+    pub(crate) fn with_prompt_lock(mut self, prompt: PromptLock) -> KeyringBackend {
+        self.prompt = Some(prompt);
+        self
+    }
+
+    /// The attributes used to search for a password. Unless foreign items are
+    /// included, only the items created by pwvlt are searched.
+    fn search_attributes<'a>(
+        &self,
+        service: &'a str,
+        username: &'a str,
+    ) -> Vec<(&'a str, &'a str)> {
+        let mut attrs = vec![("service", service), ("username", username)];
+        if !self.config.include_foreign {
+            attrs.push(("application", APPLICATION));
+        }
+        attrs
+    }
+
+    /// Calls `f` with the unlocked collection. It also initialises the slots
+    /// field in case it is None.
+    fn with_collection<T, F>(&self, f: F) -> Result<T, PwvltError>
+    where
+        F: FnOnce(&Collection) -> Result<T, PwvltError>,
+    {
+        let secret_service = SecretService::new(EncryptionType::Dh)?;
+        let collection = find_collection(&secret_service, &self.config, self.prompt.as_ref())?;
+        let mut unlocked = self.unlocked.lock().unwrap();
+        let mut slots_guard = self.slots.lock().unwrap();
+        if slots_guard.is_none() {
             let items = collection.get_all_items()?;
             let mut slots = Vec::with_capacity(items.len());
             for item in items {
                 let mut attrs: HashMap<String, String> =
                     item.get_attributes()?.into_iter().collect();
-                slots.push(Slot {
-                    username: attrs.remove("username").unwrap_or_else(|| NOT_SET.into()),
-                    service: attrs.remove("service").unwrap_or_else(|| NOT_SET.into()),
+                let foreign = attrs.get("application").map(String::as_str) != Some(APPLICATION);
+                if foreign && !self.config.include_foreign {
+                    continue;
+                }
+                slots.push(Entry {
+                    slot: Slot {
+                        username: attrs.remove("username").unwrap_or_else(|| NOT_SET.into()),
+                        service: attrs.remove("service").unwrap_or_else(|| NOT_SET.into()),
+                    },
+                    foreign,
                 });
             }
-            self.collection.replace(Some(collection));
-            self.slots.replace(Some(slots));
+            slots_guard.replace(slots);
         }
-        if let Some(collection) = &*self.collection.borrow() {
-            if collection.is_locked()? {
-                collection.unlock()?;
+        drop(slots_guard);
+        if collection.is_locked()? {
+            let prompt = self.prompt.as_ref().map(PromptLock::acquire);
+            collection.unlock()?;
+            drop(prompt);
+            if self.config.relock != Relock::Never {
+                if unlocked.is_none() {
+                    if let Some(idle) = self.config.relock_idle {
+                        relock_when_idle(
+                            Arc::downgrade(&self.unlocked),
+                            self.config.clone(),
+                            Duration::from_secs(idle),
+                        );
+                    }
+                }
+                unlocked.replace(Instant::now());
             }
+        } else if unlocked.is_some() {
+            unlocked.replace(Instant::now());
         }
-        Ok(())
+        f(&collection)
     }
 
-    /// Get slot `i`.
-    fn slot(&self, i: usize) -> Option<Slot> {
-        if let Some(slots) = &*self.slots.borrow() {
-            slots.get(i).cloned()
+    /// Get slot `i`, failing if it can't be overwritten.
+    fn slot(&self, i: usize) -> Result<Option<Slot>, PwvltError> {
+        if let Some(slots) = &*self.slots.lock().unwrap() {
+            match slots.get(i) {
+                Some(entry) if entry.foreign => Err(PwvltError::ReadOnlySlot { slot: i }),
+                Some(entry) => Ok(Some(entry.slot.clone())),
+                None => Ok(None),
+            }
         } else {
-            panic!("Did you try to unlock_collection before using slot?");
+            panic!("Did you try to get the collection before using slot?");
         }
     }
 
-    /// Removes slot `i` and pushes `slot` to the end of the `slots` vector.
+    /// Removes slot `i` (if it exists) and pushes `slot` to the end of the
+    /// `slots` vector.
     fn remove_and_add_slot(&self, i: usize, slot: Slot) {
-        if let Some(slots) = &mut *self.slots.borrow_mut() {
-            slots.remove(i);
-            slots.push(slot);
+        if let Some(slots) = &mut *self.slots.lock().unwrap() {
+            if i < slots.len() {
+                slots.remove(i);
+            }
+            slots.push(Entry {
+                slot,
+                foreign: false,
+            });
         } else {
-            panic!("Did you try to unlock_collection before using remove_and_add_slot?");
+            panic!("Did you try to get the collection before using remove_and_add_slot?");
         }
     }
 
-    fn delete_password(&self, service: &str, username: &str) -> Result<(), PwvltError> {
-        if let Some(collection) = &*self.collection.borrow() {
-            let attrs = vec![("service", service), ("username", username)];
-            let search = collection.search_items(attrs)?;
-            let item = search.get(0).ok_or(PwvltError::PasswordNotFound)?;
-            item.delete()?;
-            Ok(())
-        } else {
-            panic!("Did you try to unlock_collection before using delete_password?");
-        }
+    fn delete_password(
+        collection: &Collection,
+        service: &str,
+        username: &str,
+    ) -> Result<(), PwvltError> {
+        let attrs = vec![
+            ("service", service),
+            ("username", username),
+            ("application", APPLICATION),
+        ];
+        let search = collection.search_items(attrs)?;
+        let item = search.first().ok_or(PwvltError::PasswordNotFound)?;
+        item.delete()?;
+        Ok(())
     }
 }
 
-impl<'a> Backend for KeyringBackend<'a> {
+impl Backend for KeyringBackend {
     fn password(&self, service: &str, username: &str) -> Result<String, PwvltError> {
-        self.unlock_collection()?;
-        if let Some(collection) = &*self.collection.borrow() {
-            let attrs = vec![("service", service), ("username", username)];
-            let search = collection.search_items(attrs)?;
-            let item = search.get(0).ok_or(PwvltError::PasswordNotFound)?;
+        self.with_collection(|collection| {
+            let search = collection.search_items(self.search_attributes(service, username))?;
+            let item = search.first().ok_or(PwvltError::PasswordNotFound)?;
             let secret_bytes = item.get_secret()?;
             Ok(String::from_utf8(secret_bytes)?)
-        } else {
-            unreachable!("Unlock collection should've errored.");
-        }
+        })
     }
 
     fn set_password(
@@ -115,18 +263,16 @@ impl<'a> Backend for KeyringBackend<'a> {
         username: &str,
         password: &str,
     ) -> Result<(), PwvltError> {
-        self.unlock_collection()?;
-        // if the slot_index is not out-of-bounds, then the user is trying to
-        // replace this particular slot with new values.
-        if let Some(slot) = self.slot(slot_index) {
-            self.delete_password(&slot.service, &slot.username)?;
-            self.remove_and_add_slot(slot_index, slot);
-        };
-        if let Some(collection) = &*self.collection.borrow() {
+        self.with_collection(|collection| {
+            // if the slot_index is not out-of-bounds, then the user is trying to
+            // replace this particular slot with new values.
+            if let Some(slot) = self.slot(slot_index)? {
+                Self::delete_password(collection, &slot.service, &slot.username)?;
+            };
             let attrs = vec![
                 ("service", service),
                 ("username", username),
-                ("application", "pwvlt"),
+                ("application", APPLICATION),
             ];
             let label = &format!("Password for {} on {}", username, service)[..];
             collection.create_item(
@@ -136,33 +282,29 @@ impl<'a> Backend for KeyringBackend<'a> {
                 true, // replace
                 "text/plain",
             )?;
+            self.remove_and_add_slot(
+                slot_index,
+                Slot {
+                    service: service.into(),
+                    username: username.into(),
+                },
+            );
             Ok(())
-        } else {
-            unreachable!("Unlock collection should've errored.");
-        }
-    }
-
-    fn log_error(&self, err: PwvltError) {
-        let msg = match err {
-            PwvltError::Keyring(err) => err.to_string(),
-            PwvltError::PasswordNotFound => "Password not found in Keyring".to_string(),
-            _ => unreachable!("A KeyringBackend shouldn't generate a {} error.", err),
-        };
-        log::warn!("{}", msg);
+        })
     }
 
-    fn name(&self) -> &'static str {
+    fn name(&self) -> &str {
         "Keyring"
     }
 
     fn slots(&self) -> Result<Vec<Slot>, PwvltError> {
-        self.unlock_collection()?;
-        if let Some(slots) = &*self.slots.borrow() {
-            let mut slots = slots.clone();
+        self.with_collection(|_| Ok(()))?;
+        if let Some(slots) = &*self.slots.lock().unwrap() {
+            let mut slots: Vec<_> = slots.iter().map(|entry| entry.slot.clone()).collect();
             slots.push(Default::default());
             Ok(slots)
         } else {
-            unreachable!("Unlock collection should've errored.");
+            unreachable!("Getting the collection should've errored.");
         }
     }
 }
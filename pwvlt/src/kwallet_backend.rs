@@ -0,0 +1,236 @@
+use crate::{Backend, PwvltError, Slot};
+
+use dbus::{BusType, Connection, Message, MessageItem};
+
+/// The names and object paths kwalletd is served at, from the newest version
+/// of KDE to the oldest.
+const DAEMONS: [(&str, &str); 3] = [
+    ("org.kde.kwalletd6", "/modules/kwalletd6"),
+    ("org.kde.kwalletd5", "/modules/kwalletd5"),
+    ("org.kde.kwalletd", "/modules/kwalletd"),
+];
+const INTERFACE: &str = "org.kde.KWallet";
+/// The application id reported to kwalletd, and the folder of the wallet in
+/// which the passwords are stored.
+const APP_ID: &str = "pwvlt";
+const FOLDER: &str = "pwvlt";
+/// Opening the wallet can prompt for its password, so calls are given plenty
+/// of time.
+const TIMEOUT_MS: i32 = 60_000;
+
+/// Stores passwords in the network wallet of KWallet. Each password is an
+/// entry of the `pwvlt` folder, named `<service>/<username>`. The
+/// `Connection` of dbus 0.2 wraps a raw libdbus pointer and isn't `Send`, so
+/// the backend keeps none and connects for every operation.
+pub struct KWalletBackend {
+    service: &'static str,
+    path: &'static str,
+}
+
+fn unexpected_reply(method: &str) -> PwvltError {
+    PwvltError::KWallet(format!("Unexpected reply from kwalletd to {}.", method))
+}
+
+/// The service is the part of the entry name before the first slash, so it
+/// can't contain one.
+fn check_service(service: &str) -> Result<(), PwvltError> {
+    if service.contains('/') {
+        return Err(PwvltError::InvalidCharacter {
+            field: "service",
+            character: '/',
+        });
+    }
+    Ok(())
+}
+
+fn entry_key(service: &str, username: &str) -> String {
+    format!("{}/{}", service, username)
+}
+
+impl KWalletBackend {
+    pub fn new() -> Result<KWalletBackend, PwvltError> {
+        let connection = Connection::get_private(BusType::Session)?;
+        for &(service, path) in &DAEMONS {
+            let backend = KWalletBackend { service, path };
+            if let Ok(items) = backend.call(&connection, "isEnabled", Vec::new()) {
+                return match items.as_slice() {
+                    [MessageItem::Bool(true)] => Ok(backend),
+                    _ => Err(PwvltError::KWallet("KWallet is disabled.".into())),
+                };
+            }
+        }
+        Err(PwvltError::KWallet("kwalletd isn't running.".into()))
+    }
+
+    fn call(
+        &self,
+        connection: &Connection,
+        method: &str,
+        args: Vec<MessageItem>,
+    ) -> Result<Vec<MessageItem>, PwvltError> {
+        let mut message = Message::new_method_call(self.service, self.path, INTERFACE, method)
+            .map_err(PwvltError::KWallet)?;
+        message.append_items(&args);
+        let reply = connection.send_with_reply_and_block(message, TIMEOUT_MS)?;
+        Ok(reply.get_items())
+    }
+
+    /// Calls `f` with a connection and the handle of the open network wallet,
+    /// creating the `pwvlt` folder if needed. The handle is closed once `f`
+    /// returns.
+    fn with_wallet<T, F>(&self, f: F) -> Result<T, PwvltError>
+    where
+        F: FnOnce(&Connection, i32) -> Result<T, PwvltError>,
+    {
+        let connection = Connection::get_private(BusType::Session)?;
+        let wallet = match self
+            .call(&connection, "networkWallet", Vec::new())?
+            .as_slice()
+        {
+            [MessageItem::Str(wallet)] => wallet.clone(),
+            _ => return Err(unexpected_reply("networkWallet")),
+        };
+        let args = vec![wallet.as_str().into(), 0i64.into(), APP_ID.into()];
+        let handle = match self.call(&connection, "open", args)?.as_slice() {
+            [MessageItem::Int32(handle)] if *handle >= 0 => *handle,
+            [MessageItem::Int32(_)] => {
+                return Err(PwvltError::KWallet(format!(
+                    "Failed to open the {} wallet.",
+                    wallet
+                )))
+            }
+            _ => return Err(unexpected_reply("open")),
+        };
+        let result = self
+            .create_folder(&connection, handle, &wallet)
+            .and_then(|()| f(&connection, handle));
+        // the wallet stays open for pwvlt until its handle is closed
+        let args = vec![handle.into(), false.into(), APP_ID.into()];
+        if let Err(err) = self.call(&connection, "close", args) {
+            log::warn!("Failed to close the {} wallet: {}", wallet, err);
+        }
+        result
+    }
+
+    fn create_folder(
+        &self,
+        connection: &Connection,
+        handle: i32,
+        wallet: &str,
+    ) -> Result<(), PwvltError> {
+        let args = vec![handle.into(), FOLDER.into(), APP_ID.into()];
+        if let [MessageItem::Bool(false)] = self.call(connection, "hasFolder", args)?.as_slice() {
+            log::info!("Creating the {} folder in the {} wallet.", FOLDER, wallet);
+            let args = vec![handle.into(), FOLDER.into(), APP_ID.into()];
+            self.call(connection, "createFolder", args)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the sorted names of the entries of the `pwvlt` folder.
+    fn entries(&self, connection: &Connection, handle: i32) -> Result<Vec<String>, PwvltError> {
+        let args = vec![handle.into(), FOLDER.into(), APP_ID.into()];
+        let mut entries = match self.call(connection, "entryList", args)?.as_slice() {
+            [MessageItem::Array(items, _)] => items
+                .iter()
+                .filter_map(|item| match item {
+                    MessageItem::Str(key) if key.contains('/') => Some(key.clone()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>(),
+            _ => return Err(unexpected_reply("entryList")),
+        };
+        entries.sort();
+        Ok(entries)
+    }
+}
+
+impl Backend for KWalletBackend {
+    fn password(&self, service: &str, username: &str) -> Result<String, PwvltError> {
+        check_service(service)?;
+        let key = entry_key(service, username);
+        self.with_wallet(|connection, handle| {
+            let args = vec![
+                handle.into(),
+                FOLDER.into(),
+                key.as_str().into(),
+                APP_ID.into(),
+            ];
+            match self.call(connection, "hasEntry", args)?.as_slice() {
+                [MessageItem::Bool(true)] => {}
+                [MessageItem::Bool(false)] => return Err(PwvltError::PasswordNotFound),
+                _ => return Err(unexpected_reply("hasEntry")),
+            }
+            let args = vec![
+                handle.into(),
+                FOLDER.into(),
+                key.as_str().into(),
+                APP_ID.into(),
+            ];
+            match self.call(connection, "readPassword", args)?.as_slice() {
+                [MessageItem::Str(password)] => Ok(password.clone()),
+                _ => Err(unexpected_reply("readPassword")),
+            }
+        })
+    }
+
+    fn set_password(
+        &self,
+        slot: usize,
+        service: &str,
+        username: &str,
+        password: &str,
+    ) -> Result<(), PwvltError> {
+        check_service(service)?;
+        let key = entry_key(service, username);
+        self.with_wallet(|connection, handle| {
+            if let Some(old) = self.entries(connection, handle)?.get(slot) {
+                if *old != key {
+                    let args = vec![
+                        handle.into(),
+                        FOLDER.into(),
+                        old.as_str().into(),
+                        APP_ID.into(),
+                    ];
+                    self.call(connection, "removeEntry", args)?;
+                }
+            }
+            let args = vec![
+                handle.into(),
+                FOLDER.into(),
+                key.as_str().into(),
+                password.into(),
+                APP_ID.into(),
+            ];
+            match self.call(connection, "writePassword", args)?.as_slice() {
+                [MessageItem::Int32(0)] => Ok(()),
+                [MessageItem::Int32(_)] => {
+                    Err(PwvltError::KWallet(format!("Failed to write {}.", key)))
+                }
+                _ => Err(unexpected_reply("writePassword")),
+            }
+        })
+    }
+
+    fn name(&self) -> &str {
+        "KWallet"
+    }
+
+    fn slots(&self) -> Result<Vec<Slot>, PwvltError> {
+        self.with_wallet(|connection, handle| {
+            let mut slots: Vec<_> = self
+                .entries(connection, handle)?
+                .iter()
+                .filter_map(|key| {
+                    let mut parts = key.splitn(2, '/');
+                    Some(Slot {
+                        service: parts.next()?.into(),
+                        username: parts.next()?.into(),
+                    })
+                })
+                .collect();
+            slots.push(Default::default());
+            Ok(slots)
+        })
+    }
+}
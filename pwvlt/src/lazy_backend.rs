@@ -0,0 +1,157 @@
+use crate::config::Timeouts;
+use crate::{Backend, Event, Observer, PwvltError, Slot, SlotUsage};
+
+use std::panic::resume_unwind;
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::Duration;
+
+pub type Connect = Box<dyn Fn() -> Result<Box<dyn Backend>, PwvltError> + Send + Sync>;
+
+/// A backend which is only connected to the first time it is used. Connecting
+/// and every operation are bounded by the configured timeouts.
+pub struct LazyBackend {
+    name: String,
+    /// The name of the underlying backend once connected, which may be more
+    /// precise (e.g. the serial number of the Nitrokey which was found).
+    connected_name: OnceLock<String>,
+    connect: Arc<Connect>,
+    backend: Mutex<Option<Arc<dyn Backend>>>,
+    timeouts: Timeouts,
+    observer: Arc<dyn Observer>,
+}
+
+/// Runs `f`, giving up after `seconds` (if any). On timeout, `f` is left
+/// running on its own thread, since a blocked D-Bus or USB call can't be
+/// interrupted.
+fn with_timeout<T, F>(
+    backend: &str,
+    operation: &'static str,
+    seconds: Option<u64>,
+    f: F,
+) -> Result<T, PwvltError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, PwvltError> + Send + 'static,
+{
+    let seconds = match seconds {
+        Some(seconds) => seconds,
+        None => return f(),
+    };
+    let (tx, rx) = channel();
+    let handle = thread::spawn(move || {
+        // the receiver is gone if we already timed out
+        let _ = tx.send(f());
+    });
+    match rx.recv_timeout(Duration::from_secs(seconds)) {
+        Ok(res) => res,
+        Err(RecvTimeoutError::Timeout) => Err(PwvltError::Timeout {
+            backend: backend.into(),
+            operation,
+            seconds,
+        }),
+        Err(RecvTimeoutError::Disconnected) => match handle.join() {
+            Err(panic) => resume_unwind(panic),
+            Ok(()) => unreachable!("The operation finished without sending its result."),
+        },
+    }
+}
+
+impl LazyBackend {
+    pub fn new(
+        name: String,
+        connect: Connect,
+        timeouts: Timeouts,
+        observer: Arc<dyn Observer>,
+    ) -> LazyBackend {
+        LazyBackend {
+            name,
+            connected_name: OnceLock::new(),
+            connect: Arc::new(connect),
+            backend: Mutex::new(None),
+            timeouts,
+            observer,
+        }
+    }
+
+    /// Returns the underlying backend, connecting to it if needed.
+    fn backend(&self) -> Result<Arc<dyn Backend>, PwvltError> {
+        let mut backend = self.backend.lock().unwrap();
+        if let Some(backend) = &*backend {
+            return Ok(Arc::clone(backend));
+        }
+        let connect = Arc::clone(&self.connect);
+        let res: Result<Arc<dyn Backend>, _> =
+            with_timeout(&self.name, "connecting", self.timeouts.connect, move || {
+                connect().map(Arc::from)
+            });
+        match res {
+            Ok(connected) => {
+                self.observer.notify(&Event::BackendLoaded {
+                    backend: &self.name,
+                });
+                let _ = self.connected_name.set(connected.name().into());
+                backend.replace(Arc::clone(&connected));
+                Ok(connected)
+            }
+            Err(error) => {
+                self.observer.notify(&Event::BackendFailed {
+                    backend: &self.name,
+                    error: &error,
+                });
+                Err(error)
+            }
+        }
+    }
+
+    fn run<T, F>(&self, operation: &'static str, f: F) -> Result<T, PwvltError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&dyn Backend) -> Result<T, PwvltError> + Send + 'static,
+    {
+        let backend = self.backend()?;
+        with_timeout(&self.name, operation, self.timeouts.operation, move || {
+            f(backend.as_ref())
+        })
+    }
+}
+
+impl Backend for LazyBackend {
+    fn password(&self, service: &str, username: &str) -> Result<String, PwvltError> {
+        let (service, username) = (service.to_string(), username.to_string());
+        self.run("looking up a password", move |backend| {
+            backend.password(&service, &username)
+        })
+    }
+
+    fn set_password(
+        &self,
+        slot: usize,
+        service: &str,
+        username: &str,
+        password: &str,
+    ) -> Result<(), PwvltError> {
+        let (service, username) = (service.to_string(), username.to_string());
+        let password = password.to_string();
+        self.run("writing a password", move |backend| {
+            backend.set_password(slot, &service, &username, &password)
+        })
+    }
+
+    fn name(&self) -> &str {
+        self.connected_name.get().unwrap_or(&self.name)
+    }
+
+    fn max_password_length(&self) -> Option<usize> {
+        self.backend().ok()?.max_password_length()
+    }
+
+    fn slots(&self) -> Result<Vec<Slot>, PwvltError> {
+        self.run("listing slots", |backend| backend.slots())
+    }
+
+    fn slot_usage(&self) -> Result<Option<SlotUsage>, PwvltError> {
+        self.run("counting slots", |backend| backend.slot_usage())
+    }
+}
@@ -1,13 +1,36 @@
+#![forbid(unsafe_code)]
+
 mod config;
-pub use config::Config;
+pub use config::{Config, Kdbx, Keyring, Pass, Relock};
+mod event;
+pub use event::{Event, LogObserver, Observer};
 mod error;
-pub use error::PwvltError;
+pub use error::{Attempt, ConfigError, PwvltError};
+mod kdbx_backend;
+pub use kdbx_backend::{KdbxBackend, KdbxUnlock};
 mod keyring_backend;
 pub use keyring_backend::KeyringBackend;
+mod kwallet_backend;
+pub use kwallet_backend::KWalletBackend;
 mod nitrokey_backend;
-pub use nitrokey_backend::NitrokeyBackend;
+mod nitrokey_device;
+pub use nitrokey::{DeviceInfo, SerialNumber};
+pub use nitrokey_backend::{DeviceReport, NitrokeyBackend, Pin, PinRequest, SlotUsage};
+#[cfg(any(test, feature = "simulator"))]
+mod nitrokey_simulator;
+#[cfg(any(test, feature = "simulator"))]
+pub use nitrokey_simulator::NitrokeySimulator;
+mod pass_backend;
+pub use pass_backend::PassBackend;
 mod backend;
+mod lazy_backend;
+#[cfg(test)]
+mod temp_dir;
 pub use backend::{Backend, Slot};
 pub mod util;
 mod vault;
 pub use vault::PasswordVault;
+#[cfg(feature = "async")]
+mod async_vault;
+#[cfg(feature = "async")]
+pub use async_vault::AsyncPasswordVault;
@@ -1,81 +1,382 @@
+use crate::nitrokey_device::{Connection, Device, Safe};
 use crate::{Backend, PwvltError, Slot};
 
 use nitrokey::{
-    connect, CommandError, Device, DeviceWrapper, GetPasswordSafe, PasswordSafe, SLOT_COUNT,
+    CommandError, CommunicationError, DeviceInfo, FirmwareVersion, Model, SerialNumber,
 };
+use zeroize::Zeroizing;
 
-use std::cell::RefCell;
-use std::ptr::NonNull;
+use std::fmt;
+use std::sync::{Arc, Mutex};
 
-pub struct NitrokeyBackend<'a> {
-    device: NonNull<DeviceWrapper>,
-    pws: RefCell<Option<PasswordSafe<'a>>>,
-    unlock_hook: fn() -> Result<String, PwvltError>,
+/// The longest name, login and password (in bytes) a password safe slot can
+/// hold.
+const SLOT_NAME_LENGTH: usize = 11;
+const SLOT_LOGIN_LENGTH: usize = 32;
+const SLOT_PASSWORD_LENGTH: usize = 20;
+
+/// Checks that `value` fits in a slot field. The device stores C strings, so
+/// NUL characters can't be used either.
+fn check_field(field: &'static str, value: &str, max: usize) -> Result<(), PwvltError> {
+    if value.len() > max {
+        return Err(PwvltError::FieldTooLong {
+            field,
+            length: value.len(),
+            max,
+        });
+    }
+    if value.contains('\0') {
+        return Err(PwvltError::InvalidCharacter {
+            field,
+            character: '\0',
+        });
+    }
+    Ok(())
+}
+
+/// Passed to the unlock hook of a `NitrokeyBackend`.
+#[derive(Clone, Copy, Debug)]
+pub struct PinRequest {
+    /// The serial number of the Nitrokey, if it reports one.
+    pub serial: Option<SerialNumber>,
+    /// How many more times a wrong user pin can be entered before the
+    /// Nitrokey locks it.
+    pub retries: u8,
+    /// Whether the hook may return a pin that wasn't typed by the user (e.g.
+    /// read from the environment). This is not allowed when a single retry is
+    /// left, so that a stale pin can't lock the device.
+    pub allow_automatic: bool,
+}
+
+/// A user pin returned by the unlock hook of a `NitrokeyBackend`.
+pub struct Pin {
+    pub value: String,
+    /// Whether the pin was obtained without prompting the user.
+    pub automatic: bool,
+}
+
+impl Pin {
+    pub fn prompted(value: String) -> Pin {
+        Pin {
+            value,
+            automatic: false,
+        }
+    }
+
+    pub fn automatic(value: String) -> Pin {
+        Pin {
+            value,
+            automatic: true,
+        }
+    }
+}
+
+/// How many slots of a kind are programmed.
+#[derive(Clone, Copy, Debug)]
+pub struct SlotUsage {
+    pub programmed: usize,
+    pub total: usize,
 }
 
-impl<'a> Drop for NitrokeyBackend<'a> {
+impl fmt::Display for SlotUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{} programmed", self.programmed, self.total)
+    }
+}
+
+/// The state of a Nitrokey which can be read without unlocking it.
+#[derive(Clone, Copy, Debug)]
+pub struct DeviceReport {
+    pub model: Model,
+    pub firmware: FirmwareVersion,
+    pub serial: SerialNumber,
+    pub user_retries: u8,
+    pub admin_retries: u8,
+    pub hotp_slots: SlotUsage,
+    pub totp_slots: SlotUsage,
+}
+
+/// The service and username of each slot (`None` if the slot is not
+/// programmed).
+type SlotIndex = Vec<Option<Slot>>;
+
+pub struct NitrokeyBackend {
+    /// The thread which talks to the device. libnitrokey can only talk to one
+    /// device at a time, so it is shared by the backends of all the devices,
+    /// and only keeps the password safe of the most recently used one open.
+    connection: Arc<Connection>,
+    path: String,
+    serial: Option<SerialNumber>,
+    name: String,
+    /// Built once the safe is unlocked, so that lookups don't need to read
+    /// every slot from the device. It is invalidated when a slot is written
+    /// or a new session starts. Holding its lock serializes the operations of
+    /// the backend.
+    slot_index: Mutex<Option<SlotIndex>>,
+    unlock_hook: Box<dyn Fn(PinRequest) -> Result<Pin, PwvltError> + Send + Sync>,
+}
+
+impl Drop for NitrokeyBackend {
     fn drop(&mut self) {
-        let device = unsafe { Box::from_raw(self.device.as_ptr()) };
-        if let Err(err) = device.lock() {
-            eprintln!("Failed to lock the Nitrokey: {:?}", err);
+        if let Err(err) = self
+            .connection
+            .with_device(&self.path, |device| device.lock())
+        {
+            eprintln!("Failed to lock the {}: {}", self.name, err);
         }
     }
 }
 
-impl<'a> NitrokeyBackend<'a> {
-    pub fn new(
-        unlock_hook: fn() -> Result<String, PwvltError>,
-    ) -> Result<NitrokeyBackend<'a>, PwvltError> {
-        let device = Box::new(connect()?);
-        let device = Box::leak(device);
+impl NitrokeyBackend {
+    /// Uses the first Nitrokey found. `unlock_hook` is called to get the user
+    /// pin when the password safe is accessed and isn't open, and again after
+    /// every wrong pin. The pin isn't kept: the safe stays open until another
+    /// Nitrokey is used, a device command (e.g. `user_retry_count`) is sent,
+    /// or the backend is dropped.
+    pub fn new<F>(unlock_hook: F) -> Result<NitrokeyBackend, PwvltError>
+    where
+        F: Fn(PinRequest) -> Result<Pin, PwvltError> + Send + Sync + 'static,
+    {
+        NitrokeyBackend::with_serial(None, unlock_hook)
+    }
+
+    /// Uses the Nitrokey with the given serial number, or the first one found
+    /// if `serial` is `None`.
+    pub fn with_serial<F>(
+        serial: Option<SerialNumber>,
+        unlock_hook: F,
+    ) -> Result<NitrokeyBackend, PwvltError>
+    where
+        F: Fn(PinRequest) -> Result<Pin, PwvltError> + Send + Sync + 'static,
+    {
+        NitrokeyBackend::with_connection(Connection::shared()?, serial, unlock_hook)
+    }
+
+    pub(crate) fn with_connection<F>(
+        connection: Arc<Connection>,
+        serial: Option<SerialNumber>,
+        unlock_hook: F,
+    ) -> Result<NitrokeyBackend, PwvltError>
+    where
+        F: Fn(PinRequest) -> Result<Pin, PwvltError> + Send + Sync + 'static,
+    {
+        let mut devices = connection.devices()?.into_iter();
+        let device = match serial {
+            Some(serial) => devices
+                .find(|device| device.serial_number == Some(serial))
+                .ok_or_else(|| PwvltError::NitrokeyNotFound {
+                    serial: serial.to_string(),
+                })?,
+            None => devices
+                .next()
+                .ok_or_else(|| nitrokey::Error::from(CommunicationError::NotConnected))?,
+        };
         Ok(NitrokeyBackend {
-            device: NonNull::new(device).unwrap(),
-            pws: RefCell::new(None),
-            unlock_hook,
+            connection,
+            path: device.path,
+            serial: device.serial_number,
+            name: NitrokeyBackend::display_name(device.serial_number),
+            slot_index: Mutex::new(None),
+            unlock_hook: Box::new(unlock_hook),
         })
     }
 
-    fn device(&self) -> &'a DeviceWrapper {
-        unsafe { std::mem::transmute(self.device.as_ref()) }
+    /// Returns the Nitrokeys which are currently connected.
+    pub fn devices() -> Result<Vec<DeviceInfo>, PwvltError> {
+        Ok(Connection::shared()?.devices()?)
     }
 
-    pub fn unlock_safe(&self) -> Result<(), PwvltError> {
-        if self.pws.borrow().is_some() {
-            return Ok(());
+    /// The name of a backend which uses the Nitrokey with the given serial
+    /// number (e.g. `Nitrokey 0x0001b2c3`).
+    pub(crate) fn display_name(serial: Option<SerialNumber>) -> String {
+        match serial {
+            Some(serial) => format!("Nitrokey {}", serial),
+            None => "Nitrokey".into(),
         }
+    }
 
-        let user_count = self.device().get_user_retry_count();
-        if user_count < 1 {
-            log::error!("Nitrokey must be unlocked using the admin pin!");
-            log::error!("Please use the Nitrokey app to reset the user pin! Exiting.");
-            return Err(PwvltError::Skip);
-        };
-        let pin = (self.unlock_hook)()?;
-        let pws = self
-            .device()
-            .get_password_safe(&pin)
-            .map_err(PwvltError::from)?;
-        self.pws.replace(Some(pws));
-        Ok(())
+    pub fn serial(&self) -> Option<SerialNumber> {
+        self.serial
     }
-}
 
-impl<'a> Backend for NitrokeyBackend<'a> {
-    fn password(&self, service: &str, username: &str) -> Result<String, PwvltError> {
-        self.unlock_safe()?;
-        let pws_ref = &*self.pws.borrow();
-        let pws = if let Some(pws) = pws_ref {
-            pws
-        } else {
-            unreachable!("unlock_safe should've errored");
-        };
+    pub fn user_retry_count(&self) -> Result<u8, PwvltError> {
+        Ok(self
+            .connection
+            .with_device(&self.path, |device| device.user_retry_count())?)
+    }
+
+    pub fn admin_retry_count(&self) -> Result<u8, PwvltError> {
+        Ok(self
+            .connection
+            .with_device(&self.path, |device| device.admin_retry_count())?)
+    }
+
+    /// Reports the model, firmware, retry counters and OTP slot usage of the
+    /// device.
+    pub fn device_info(&self) -> Result<DeviceReport, PwvltError> {
+        Ok(self
+            .connection
+            .with_device(&self.path, |device| device.report())?)
+    }
+
+    /// Returns how many slots of the password safe are programmed. This
+    /// unlocks the password safe.
+    pub fn password_safe_usage(&self) -> Result<SlotUsage, PwvltError> {
+        let mut slot_index = self.slot_index.lock().unwrap();
+        let slots = self.slot_index(&mut slot_index)?;
+        Ok(SlotUsage {
+            programmed: slots.iter().filter(|slot| slot.is_some()).count(),
+            total: slots.len(),
+        })
+    }
+
+    /// Sends an admin command, which closes the password safe. `check` fails
+    /// if the pin it needs is locked.
+    fn admin_command<F>(
+        &self,
+        check: fn(u8, u8) -> Result<(), PwvltError>,
+        f: F,
+    ) -> Result<(), PwvltError>
+    where
+        F: FnOnce(&mut dyn Device) -> Result<(), nitrokey::Error> + Send + 'static,
+    {
+        let mut slot_index = self.slot_index.lock().unwrap();
+        let res = self.connection.with_device(&self.path, move |device| {
+            let retries = (device.user_retry_count()?, device.admin_retry_count()?);
+            Ok(check(retries.0, retries.1).and_then(|()| Ok(f(device)?)))
+        });
+        // the next access to the password safe starts a new session
+        slot_index.take();
+        res?
+    }
+
+    pub fn change_user_pin(&self, current: &str, new: &str) -> Result<(), PwvltError> {
+        let current = Zeroizing::new(current.to_owned());
+        let new = Zeroizing::new(new.to_owned());
+        self.admin_command(
+            |user_retries, _| match user_retries {
+                0 => Err(PwvltError::UserPinLocked),
+                _ => Ok(()),
+            },
+            move |device| device.change_user_pin(&current, &new),
+        )
+    }
+
+    /// Unblocks a locked user pin, setting it to `new_user_pin`.
+    pub fn unblock_user_pin(&self, admin_pin: &str, new_user_pin: &str) -> Result<(), PwvltError> {
+        let admin_pin = Zeroizing::new(admin_pin.to_owned());
+        let new_user_pin = Zeroizing::new(new_user_pin.to_owned());
+        self.admin_command(check_admin_retries, move |device| {
+            device.unlock_user_pin(&admin_pin, &new_user_pin)
+        })
+    }
+
+    /// Builds a new AES key for the password safe, which is needed before a
+    /// new (or factory reset) device can use it. This erases all the slots of
+    /// the password safe.
+    pub fn init_password_safe(&self, admin_pin: &str) -> Result<(), PwvltError> {
+        let admin_pin = Zeroizing::new(admin_pin.to_owned());
+        self.admin_command(check_admin_retries, move |device| {
+            device.build_aes_key(&admin_pin)
+        })
+    }
+
+    pub fn unlock_safe(&self) -> Result<(), PwvltError> {
+        let mut slot_index = self.slot_index.lock().unwrap();
+        self.with_safe(&mut slot_index, |_| Ok(()))
+    }
+
+    /// Calls `f` with the password safe of the device. If it isn't open, the
+    /// unlock hook is called and a new session starts, clearing `slot_index`.
+    fn with_safe<T, F>(&self, slot_index: &mut Option<SlotIndex>, f: F) -> Result<T, PwvltError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut dyn Safe) -> Result<T, nitrokey::Error> + Clone + Send + 'static,
+    {
+        // an automatic pin which turned out to be wrong isn't tried again
+        let mut allow_automatic = true;
+        match self.connection.with_safe(&self.path, None, f.clone()) {
+            Ok(Some(res)) => return Ok(res),
+            Ok(None) => {}
+            // the safe was locked behind our back, e.g. by another
+            // application which may have changed the pin as well
+            Err(nitrokey::Error::CommandError(CommandError::NotAuthorized)) => {
+                log::warn!("{} was locked, it must be unlocked again.", self.name);
+                self.connection.close_safe(&self.path)?;
+                allow_automatic = false;
+            }
+            Err(err) => return Err(err.into()),
+        }
+        slot_index.take();
 
-        for slot in 0..SLOT_COUNT {
-            if pws.get_slot_name(slot)? == service && pws.get_slot_login(slot)? == username {
-                return pws.get_slot_password(slot).map_err(PwvltError::from);
+        loop {
+            let retries = self.user_retry_count()?;
+            if retries < 1 {
+                log::error!("{} must be unlocked using the admin pin!", self.name);
+                return Err(PwvltError::UserPinLocked);
+            }
+            let request = PinRequest {
+                serial: self.serial,
+                retries,
+                allow_automatic: allow_automatic && retries > 1,
+            };
+            let pin = (self.unlock_hook)(request)?;
+            if pin.automatic && !request.allow_automatic {
+                return Err(PwvltError::AutomaticPinRefused { retries });
+            }
+            let value = Zeroizing::new(pin.value);
+            if value.is_empty() {
+                return Err(PwvltError::PinCancelled);
+            }
+            match self
+                .connection
+                .with_safe(&self.path, Some(value), f.clone())
+            {
+                Ok(Some(res)) => return Ok(res),
+                Ok(None) => unreachable!("The safe is unlocked when a pin is given."),
+                Err(nitrokey::Error::CommandError(CommandError::WrongPassword)) => {
+                    log::warn!("Wrong user pin for {}.", self.name);
+                    allow_automatic &= !pin.automatic;
+                }
+                Err(err) => return Err(err.into()),
             }
         }
-        Err(PwvltError::PasswordNotFound)
+    }
+
+    /// Returns the slot index, building it if needed.
+    fn slot_index<'a>(
+        &self,
+        slot_index: &'a mut Option<SlotIndex>,
+    ) -> Result<&'a [Option<Slot>], PwvltError> {
+        if slot_index.is_none() {
+            let slots = self.with_safe(slot_index, |safe| safe.slots())?;
+            log::info!("Indexed {} slots.", self.name);
+            slot_index.replace(slots);
+        }
+        Ok(slot_index.as_deref().unwrap_or_default())
+    }
+}
+
+/// Fails if the admin pin can't be used anymore.
+fn check_admin_retries(_user_retries: u8, admin_retries: u8) -> Result<(), PwvltError> {
+    if admin_retries < 1 {
+        return Err(PwvltError::AdminPinLocked);
+    }
+    Ok(())
+}
+
+impl Backend for NitrokeyBackend {
+    fn password(&self, service: &str, username: &str) -> Result<String, PwvltError> {
+        let mut slot_index = self.slot_index.lock().unwrap();
+        let slot = self
+            .slot_index(&mut slot_index)?
+            .iter()
+            .position(|slot| match slot {
+                Some(slot) => slot.service == service && slot.username == username,
+                None => false,
+            })
+            .ok_or(PwvltError::PasswordNotFound)?;
+        self.with_safe(&mut slot_index, move |safe| safe.password(slot as u8))
     }
 
     fn set_password(
@@ -85,59 +386,282 @@ impl<'a> Backend for NitrokeyBackend<'a> {
         username: &str,
         password: &str,
     ) -> Result<(), PwvltError> {
-        self.unlock_safe()?;
-        let pws_ref = &*self.pws.borrow();
-        let pws = if let Some(pws) = pws_ref {
-            pws
-        } else {
-            unreachable!("unlock_safe should've errored");
-        };
-
-        pws.write_slot(slot as u8, service, username, password)?;
-        Ok(())
+        check_field("service", service, SLOT_NAME_LENGTH)?;
+        check_field("username", username, SLOT_LOGIN_LENGTH)?;
+        check_field("password", password, SLOT_PASSWORD_LENGTH)?;
+        let (service, username) = (service.to_owned(), username.to_owned());
+        let password = Zeroizing::new(password.to_owned());
+        let mut slot_index = self.slot_index.lock().unwrap();
+        let res = self.with_safe(&mut slot_index, move |safe| {
+            safe.write_slot(slot as u8, &service, &username, &password)
+        });
+        slot_index.take();
+        res
     }
 
-    fn log_error(&self, err: PwvltError) {
-        let message = match err {
-            PwvltError::PasswordNotFound => "Password not found on Nitrokey!".into(),
-            PwvltError::Skip => "Skipping Nitrokey search...".into(),
-            PwvltError::Nitrokey(nke) => match nke {
-                CommandError::Undefined => "Couldn't connect to the Nitrokey!".into(),
-                CommandError::WrongPassword => "User pin was incorrect.".into(),
-                err => format!("Nitrokey error: {}", err),
-            },
-            err => unreachable!("A NitrokeyKeyBackend shouldn't generate a {} error.", err),
-        };
-        log::warn!("{}", message);
+    fn name(&self) -> &str {
+        &self.name
     }
 
-    fn name(&self) -> &'static str {
-        "Nitrokey"
+    fn max_password_length(&self) -> Option<usize> {
+        Some(SLOT_PASSWORD_LENGTH)
     }
 
     fn slots(&self) -> Result<Vec<Slot>, PwvltError> {
-        self.unlock_safe()?;
-        let pws_ref = &*self.pws.borrow();
-        let pws = if let Some(pws) = pws_ref {
-            pws
-        } else {
-            unreachable!("unlock_safe should've errored");
+        let mut slot_index = self.slot_index.lock().unwrap();
+        Ok(self
+            .slot_index(&mut slot_index)?
+            .iter()
+            .map(|slot| slot.clone().unwrap_or_default())
+            .collect())
+    }
+
+    fn slot_usage(&self) -> Result<Option<SlotUsage>, PwvltError> {
+        self.password_safe_usage().map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::NitrokeySimulator;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Returns an unlock hook which types `pins` in order (repeating the last
+    /// one), and the number of times it was called.
+    fn typed_pins(
+        pins: &[&'static str],
+    ) -> (
+        Arc<AtomicUsize>,
+        impl Fn(PinRequest) -> Result<Pin, PwvltError> + Send + Sync + 'static,
+    ) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let pins = pins.to_vec();
+        let counter = Arc::clone(&calls);
+        let hook = move |_| {
+            let i = counter.fetch_add(1, Ordering::SeqCst);
+            Ok(Pin::prompted(pins[i.min(pins.len() - 1)].into()))
         };
+        (calls, hook)
+    }
 
-        let slots = pws
-            .get_slot_status()?
-            .iter()
-            .enumerate()
-            .map(|(slot, programmed)| {
-                if *programmed {
-                    let service = pws.get_slot_name(slot as u8).unwrap_or_else(|_| "".into());
-                    let username = pws.get_slot_login(slot as u8).unwrap_or_else(|_| "".into());
-                    Slot { service, username }
-                } else {
-                    Default::default()
+    #[test]
+    fn the_session_is_kept_without_the_pin() {
+        let simulator = NitrokeySimulator::new();
+        let serial = simulator.add_device("0x00000001");
+        simulator.write_slot(serial, 0, "github", "alice", "secret");
+        let (calls, hook) = typed_pins(&[NitrokeySimulator::USER_PIN]);
+        let nk = simulator.backend(Some(serial), hook).unwrap();
+
+        assert_eq!(nk.password("github", "alice").unwrap(), "secret");
+        // the safe stays open, so a changed pin isn't noticed
+        simulator.with_device(serial, |device| device.user_pin = "654321".into());
+        assert_eq!(nk.password("github", "alice").unwrap(), "secret");
+        nk.set_password(1, "gitlab", "bob", "hunter2").unwrap();
+        assert_eq!(nk.password("gitlab", "bob").unwrap(), "hunter2");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn a_wrong_pin_is_asked_again() {
+        let simulator = NitrokeySimulator::new();
+        let serial = simulator.add_device("0x00000001");
+        let (calls, hook) = typed_pins(&["000000", NitrokeySimulator::USER_PIN]);
+        let nk = simulator.backend(Some(serial), hook).unwrap();
+
+        nk.unlock_safe().unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        simulator.with_device(serial, |device| {
+            assert!(device.unlocked);
+            assert_eq!(device.user_retries, 3);
+        });
+    }
+
+    #[test]
+    fn switching_devices_asks_for_the_pin_again() {
+        let simulator = NitrokeySimulator::new();
+        let first = simulator.add_device("0x00000001");
+        let second = simulator.add_device("0x00000002");
+        simulator.write_slot(first, 0, "github", "alice", "first");
+        simulator.write_slot(second, 0, "github", "alice", "second");
+        let (first_calls, hook) = typed_pins(&[NitrokeySimulator::USER_PIN]);
+        let first_nk = simulator.backend(Some(first), hook).unwrap();
+        let (second_calls, hook) = typed_pins(&[NitrokeySimulator::USER_PIN]);
+        let second_nk = simulator.backend(Some(second), hook).unwrap();
+
+        for _ in 0..2 {
+            assert_eq!(first_nk.password("github", "alice").unwrap(), "first");
+            assert_eq!(second_nk.password("github", "alice").unwrap(), "second");
+        }
+        assert_eq!(first_calls.load(Ordering::SeqCst), 2);
+        assert_eq!(second_calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn dropping_the_backend_locks_the_device() {
+        let simulator = NitrokeySimulator::new();
+        let serial = simulator.add_device("0x00000001");
+        let (_, hook) = typed_pins(&[NitrokeySimulator::USER_PIN]);
+        let nk = simulator.backend(Some(serial), hook).unwrap();
+
+        nk.unlock_safe().unwrap();
+        assert!(simulator.with_device(serial, |device| device.unlocked));
+        drop(nk);
+        assert!(!simulator.with_device(serial, |device| device.unlocked));
+    }
+
+    #[test]
+    fn the_slot_index_skips_unprogrammed_slots() {
+        let simulator = NitrokeySimulator::new();
+        let serial = simulator.add_device("0x00000001");
+        simulator.write_slot(serial, 0, "github", "alice", "first");
+        simulator.write_slot(serial, 3, "gitlab", "bob", "second");
+        simulator.write_slot(serial, 7, "mail", "carol", "third");
+        let (_, hook) = typed_pins(&[NitrokeySimulator::USER_PIN]);
+        let nk = simulator.backend(Some(serial), hook).unwrap();
+
+        assert_eq!(nk.password("mail", "carol").unwrap(), "third");
+        assert_eq!(simulator.names_read(), [0, 3, 7]);
+        assert_eq!(nk.slots().unwrap()[3].service, "gitlab");
+        assert_eq!(nk.slots().unwrap()[4].service, Slot::default().service);
+        let usage = nk.password_safe_usage().unwrap();
+        assert_eq!((usage.programmed, usage.total), (3, 16));
+        // lookups use the index
+        let commands = simulator.commands();
+        assert_eq!(nk.password("github", "alice").unwrap(), "first");
+        assert_eq!(simulator.commands(), commands + 1);
+        assert_eq!(simulator.names_read(), [0, 3, 7]);
+    }
+
+    #[test]
+    fn the_slot_index_is_cleared_after_set_password() {
+        let simulator = NitrokeySimulator::new();
+        let serial = simulator.add_device("0x00000001");
+        simulator.write_slot(serial, 0, "github", "alice", "first");
+        let (_, hook) = typed_pins(&[NitrokeySimulator::USER_PIN]);
+        let nk = simulator.backend(Some(serial), hook).unwrap();
+
+        assert_eq!(nk.password("github", "alice").unwrap(), "first");
+        nk.set_password(0, "gitlab", "bob", "second").unwrap();
+        assert!(nk.slot_index.lock().unwrap().is_none());
+        assert!(matches!(
+            nk.password("github", "alice"),
+            Err(PwvltError::PasswordNotFound)
+        ));
+        assert_eq!(nk.password("gitlab", "bob").unwrap(), "second");
+        assert_eq!(simulator.names_read(), [0, 0]);
+    }
+
+    #[test]
+    fn a_lost_session_is_unlocked_again_without_automatic_pins() {
+        let simulator = NitrokeySimulator::new();
+        let serial = simulator.add_device("0x00000001");
+        simulator.write_slot(serial, 0, "github", "alice", "secret");
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&requests);
+        let nk = simulator
+            .backend(Some(serial), move |request: PinRequest| {
+                seen.lock().unwrap().push(request.allow_automatic);
+                let pin = NitrokeySimulator::USER_PIN.into();
+                match request.allow_automatic {
+                    true => Ok(Pin::automatic(pin)),
+                    false => Ok(Pin::prompted(pin)),
                 }
             })
-            .collect();
-        Ok(slots)
+            .unwrap();
+
+        assert_eq!(nk.password("github", "alice").unwrap(), "secret");
+        simulator.with_device(serial, |device| device.unlocked = false);
+        assert_eq!(nk.password("github", "alice").unwrap(), "secret");
+        assert_eq!(*requests.lock().unwrap(), [true, false]);
+        // the slots are indexed again in the new session
+        assert!(nk.slot_index.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn a_locked_admin_pin_is_reported() {
+        let simulator = NitrokeySimulator::new();
+        let serial = simulator.add_device("0x00000001");
+        simulator.with_device(serial, |device| device.admin_retries = 0);
+        let (_, hook) = typed_pins(&[NitrokeySimulator::USER_PIN]);
+        let nk = simulator.backend(Some(serial), hook).unwrap();
+
+        assert!(matches!(
+            nk.unblock_user_pin(NitrokeySimulator::ADMIN_PIN, "654321"),
+            Err(PwvltError::AdminPinLocked)
+        ));
+        assert!(matches!(
+            nk.init_password_safe(NitrokeySimulator::ADMIN_PIN),
+            Err(PwvltError::AdminPinLocked)
+        ));
+        // the pin isn't sent, so the counter stays at 0
+        simulator.with_device(serial, |device| assert_eq!(device.admin_retries, 0));
+    }
+
+    #[test]
+    fn a_locked_user_pin_is_reported() {
+        let simulator = NitrokeySimulator::new();
+        let serial = simulator.add_device("0x00000001");
+        simulator.with_device(serial, |device| device.user_retries = 0);
+        let (calls, hook) = typed_pins(&[NitrokeySimulator::USER_PIN]);
+        let nk = simulator.backend(Some(serial), hook).unwrap();
+
+        assert!(matches!(
+            nk.change_user_pin(NitrokeySimulator::USER_PIN, "654321"),
+            Err(PwvltError::UserPinLocked)
+        ));
+        assert!(matches!(nk.unlock_safe(), Err(PwvltError::UserPinLocked)));
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+
+        nk.unblock_user_pin(NitrokeySimulator::ADMIN_PIN, "654321")
+            .unwrap();
+        simulator.with_device(serial, |device| {
+            assert_eq!(device.user_retries, 3);
+            assert_eq!(device.user_pin, "654321");
+        });
+    }
+
+    #[test]
+    fn the_session_is_reset_after_a_pin_change() {
+        let simulator = NitrokeySimulator::new();
+        let serial = simulator.add_device("0x00000001");
+        simulator.write_slot(serial, 0, "github", "alice", "secret");
+        let (calls, hook) = typed_pins(&[NitrokeySimulator::USER_PIN, "654321"]);
+        let nk = simulator.backend(Some(serial), hook).unwrap();
+
+        assert_eq!(nk.password("github", "alice").unwrap(), "secret");
+        nk.change_user_pin(NitrokeySimulator::USER_PIN, "654321")
+            .unwrap();
+        assert!(nk.slot_index.lock().unwrap().is_none());
+        assert_eq!(nk.password("github", "alice").unwrap(), "secret");
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn init_password_safe_erases_the_slots() {
+        let simulator = NitrokeySimulator::new();
+        let serial = simulator.add_device("0x00000001");
+        simulator.write_slot(serial, 0, "github", "alice", "secret");
+        let (_, hook) = typed_pins(&[NitrokeySimulator::USER_PIN]);
+        let nk = simulator.backend(Some(serial), hook).unwrap();
+
+        assert_eq!(nk.password_safe_usage().unwrap().programmed, 1);
+        nk.init_password_safe(NitrokeySimulator::ADMIN_PIN).unwrap();
+        assert_eq!(nk.password_safe_usage().unwrap().programmed, 0);
+        assert!(matches!(
+            nk.password("github", "alice"),
+            Err(PwvltError::PasswordNotFound)
+        ));
+    }
+
+    #[test]
+    fn the_name_shows_the_connected_serial() {
+        let simulator = NitrokeySimulator::new();
+        simulator.add_device("0x0001b2c3");
+        let (_, hook) = typed_pins(&[NitrokeySimulator::USER_PIN]);
+        let nk = simulator.backend(None, hook).unwrap();
+
+        assert_eq!(nk.name(), "Nitrokey 0x0001b2c3");
     }
 }
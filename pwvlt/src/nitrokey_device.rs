@@ -0,0 +1,403 @@
+//! The connection to the Nitrokeys. libnitrokey can only talk to one device at
+//! a time, from behind a global lock, and an unlocked password safe borrows
+//! the device it belongs to. All of this is owned by a dedicated thread, which
+//! keeps the password safe of the device it is connected to open between
+//! operations, so that the user pin doesn't need to be kept around.
+
+use crate::{DeviceReport, Slot, SlotUsage};
+
+use nitrokey::{
+    CommandError, CommunicationError, Device as _, DeviceInfo, DeviceWrapper, Error,
+    GenerateOtp as _, GetPasswordSafe as _, PasswordSafe,
+};
+use zeroize::Zeroizing;
+
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::thread::{self, JoinHandle};
+
+const HOTP_SLOT_COUNT: u8 = 3;
+const TOTP_SLOT_COUNT: u8 = 15;
+
+/// Gives access to the connected Nitrokeys. Connecting to a device borrows
+/// the manager, as libnitrokey only talks to one device at a time.
+pub(crate) trait Manager {
+    fn devices(&mut self) -> Result<Vec<DeviceInfo>, Error>;
+    fn connect(&mut self, path: &str) -> Result<Box<dyn Device + '_>, Error>;
+}
+
+/// The commands pwvlt sends to a connected Nitrokey.
+pub(crate) trait Device {
+    fn user_retry_count(&self) -> Result<u8, Error>;
+    fn admin_retry_count(&self) -> Result<u8, Error>;
+    fn report(&self) -> Result<DeviceReport, Error>;
+    /// Unlocks the password safe, which borrows the device until it is
+    /// dropped.
+    fn password_safe(&mut self, pin: &str) -> Result<Box<dyn Safe + '_>, Error>;
+    fn change_user_pin(&mut self, current: &str, new: &str) -> Result<(), Error>;
+    fn unlock_user_pin(&mut self, admin_pin: &str, user_pin: &str) -> Result<(), Error>;
+    fn build_aes_key(&mut self, admin_pin: &str) -> Result<(), Error>;
+    fn lock(&mut self) -> Result<(), Error>;
+}
+
+/// The commands pwvlt sends to an unlocked password safe.
+pub(crate) trait Safe {
+    /// Returns the name and login of every slot (`None` if the slot is not
+    /// programmed). Only the programmed slots are read.
+    fn slots(&self) -> Result<Vec<Option<Slot>>, Error>;
+    fn password(&self, slot: u8) -> Result<String, Error>;
+    fn write_slot(
+        &mut self,
+        slot: u8,
+        name: &str,
+        login: &str,
+        password: &str,
+    ) -> Result<(), Error>;
+}
+
+impl Manager for MutexGuard<'static, nitrokey::Manager> {
+    fn devices(&mut self) -> Result<Vec<DeviceInfo>, Error> {
+        nitrokey::list_devices()
+    }
+
+    fn connect(&mut self, path: &str) -> Result<Box<dyn Device + '_>, Error> {
+        Ok(Box::new(self.connect_path(path)?))
+    }
+}
+
+/// Counts the programmed slots, using `name` to read the name of each slot.
+fn otp_usage<F>(total: u8, name: F) -> Result<SlotUsage, Error>
+where
+    F: Fn(u8) -> Result<String, Error>,
+{
+    let mut programmed = 0;
+    for slot in 0..total {
+        match name(slot) {
+            Ok(_) => programmed += 1,
+            Err(Error::CommandError(CommandError::SlotNotProgrammed)) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(SlotUsage {
+        programmed,
+        total: total.into(),
+    })
+}
+
+impl Device for DeviceWrapper<'_> {
+    fn user_retry_count(&self) -> Result<u8, Error> {
+        self.get_user_retry_count()
+    }
+
+    fn admin_retry_count(&self) -> Result<u8, Error> {
+        self.get_admin_retry_count()
+    }
+
+    fn report(&self) -> Result<DeviceReport, Error> {
+        let status = self.get_status()?;
+        Ok(DeviceReport {
+            model: self.get_model(),
+            firmware: status.firmware_version,
+            serial: status.serial_number,
+            user_retries: self.get_user_retry_count()?,
+            admin_retries: self.get_admin_retry_count()?,
+            hotp_slots: otp_usage(HOTP_SLOT_COUNT, |slot| self.get_hotp_slot_name(slot))?,
+            totp_slots: otp_usage(TOTP_SLOT_COUNT, |slot| self.get_totp_slot_name(slot))?,
+        })
+    }
+
+    fn password_safe(&mut self, pin: &str) -> Result<Box<dyn Safe + '_>, Error> {
+        Ok(Box::new(self.get_password_safe(pin)?))
+    }
+
+    fn change_user_pin(&mut self, current: &str, new: &str) -> Result<(), Error> {
+        nitrokey::Device::change_user_pin(self, current, new)
+    }
+
+    fn unlock_user_pin(&mut self, admin_pin: &str, user_pin: &str) -> Result<(), Error> {
+        nitrokey::Device::unlock_user_pin(self, admin_pin, user_pin)
+    }
+
+    fn build_aes_key(&mut self, admin_pin: &str) -> Result<(), Error> {
+        nitrokey::Device::build_aes_key(self, admin_pin)
+    }
+
+    fn lock(&mut self) -> Result<(), Error> {
+        nitrokey::Device::lock(self)
+    }
+}
+
+impl Safe for PasswordSafe<'_, '_> {
+    fn slots(&self) -> Result<Vec<Option<Slot>>, Error> {
+        let mut slots = Vec::new();
+        for slot in self.get_slots()? {
+            slots.push(match slot {
+                Some(slot) => Some(Slot {
+                    service: slot.get_name()?,
+                    username: slot.get_login()?,
+                }),
+                None => None,
+            });
+        }
+        Ok(slots)
+    }
+
+    fn password(&self, slot: u8) -> Result<String, Error> {
+        self.get_slot(slot)?.get_password()
+    }
+
+    fn write_slot(
+        &mut self,
+        slot: u8,
+        name: &str,
+        login: &str,
+        password: &str,
+    ) -> Result<(), Error> {
+        PasswordSafe::write_slot(self, slot, name, login, password)
+    }
+}
+
+type ManagerJob = Box<dyn FnOnce(&mut dyn Manager) + Send>;
+type DeviceJob = Box<dyn FnOnce(Result<&mut dyn Device, Error>) + Send>;
+/// Gets `None` if the password safe isn't unlocked and no pin was given.
+type SafeJob = Box<dyn FnOnce(Result<Option<&mut dyn Safe>, Error>) + Send>;
+
+/// An operation run by the connection thread. Each job sends its result back
+/// on a channel of its own.
+enum Job {
+    /// Runs with the manager, disconnecting from the current device.
+    Manager(ManagerJob),
+    /// Runs with the device at `path`, closing its password safe.
+    Device { path: String, run: DeviceJob },
+    /// Runs with the password safe of the device at `path`. If it isn't open
+    /// already, it is unlocked using `pin`.
+    Safe {
+        path: String,
+        pin: Option<Zeroizing<String>>,
+        run: SafeJob,
+    },
+}
+
+impl Job {
+    fn path(&self) -> Option<&str> {
+        match self {
+            Job::Manager(_) => None,
+            Job::Device { path, .. } | Job::Safe { path, .. } => Some(path),
+        }
+    }
+
+    fn fail(self, err: Error) {
+        match self {
+            Job::Manager(_) => unreachable!("Manager jobs can't fail to connect."),
+            Job::Device { run, .. } => run(Err(err)),
+            Job::Safe { run, .. } => run(Err(err)),
+        }
+    }
+}
+
+/// Runs the jobs until the connection is dropped.
+fn serve(manager: &mut dyn Manager, jobs: Receiver<Job>) {
+    let mut next = jobs.recv().ok();
+    while let Some(job) = next {
+        next = match job.path().map(String::from) {
+            None => {
+                if let Job::Manager(run) = job {
+                    run(manager);
+                }
+                jobs.recv().ok()
+            }
+            Some(path) => match manager.connect(&path) {
+                Ok(mut device) => serve_device(&path, device.as_mut(), job, &jobs),
+                Err(err) => {
+                    job.fail(err);
+                    jobs.recv().ok()
+                }
+            },
+        };
+    }
+}
+
+/// Runs `job` and the following jobs for the device at `path`. Returns the
+/// first job which needs another device (or the manager), or `None` once the
+/// connection is dropped.
+fn serve_device(
+    path: &str,
+    device: &mut dyn Device,
+    job: Job,
+    jobs: &Receiver<Job>,
+) -> Option<Job> {
+    let mut next = Some(job);
+    loop {
+        let job = match next.take() {
+            Some(job) => job,
+            None => jobs.recv().ok()?,
+        };
+        if job.path() != Some(path) {
+            return Some(job);
+        }
+        match job {
+            Job::Device { run, .. } => run(Ok(&mut *device)),
+            Job::Safe { pin: None, run, .. } => run(Ok(None)),
+            Job::Safe {
+                pin: Some(pin),
+                run,
+                ..
+            } => match device.password_safe(&pin) {
+                Ok(mut safe) => {
+                    drop(pin);
+                    run(Ok(Some(safe.as_mut())));
+                    next = Some(serve_safe(path, safe.as_mut(), jobs)?);
+                }
+                Err(err) => run(Err(err)),
+            },
+            Job::Manager(_) => unreachable!(),
+        }
+    }
+}
+
+/// Runs the jobs which only need the open password safe of the device at
+/// `path`. Returns the first other job, or `None` once the connection is
+/// dropped.
+fn serve_safe(path: &str, safe: &mut dyn Safe, jobs: &Receiver<Job>) -> Option<Job> {
+    loop {
+        match jobs.recv().ok()? {
+            // the safe is already open, so the pin isn't needed
+            Job::Safe {
+                path: target, run, ..
+            } if target == path => run(Ok(Some(safe))),
+            job => return Some(job),
+        }
+    }
+}
+
+/// The connection to libnitrokey, served by a dedicated thread which owns
+/// the manager and the device it is connected to. The thread stops, releasing
+/// the manager, when the connection is dropped.
+pub(crate) struct Connection {
+    jobs: Option<Sender<Job>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.jobs.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// The connection to the real devices, shared by all the Nitrokey backends.
+static SHARED: Mutex<Weak<Connection>> = Mutex::new(Weak::new());
+
+impl Connection {
+    /// Starts a thread which gets its manager from `open`.
+    pub(crate) fn open<M, F>(open: F) -> Result<Connection, Error>
+    where
+        M: Manager,
+        F: FnOnce() -> Result<M, Error> + Send + 'static,
+    {
+        let (jobs, receiver) = channel();
+        let (ready_tx, ready_rx) = channel();
+        let thread = thread::spawn(move || match open() {
+            Ok(mut manager) => {
+                let _ = ready_tx.send(Ok(()));
+                serve(&mut manager, receiver);
+            }
+            Err(err) => {
+                let _ = ready_tx.send(Err(err));
+            }
+        });
+        match ready_rx.recv() {
+            Ok(Ok(())) => Ok(Connection {
+                jobs: Some(jobs),
+                thread: Some(thread),
+            }),
+            Ok(Err(err)) => Err(err),
+            Err(_) => Err(CommunicationError::NotConnected.into()),
+        }
+    }
+
+    /// Returns the connection to the real devices, opening it if needed.
+    pub(crate) fn shared() -> Result<Arc<Connection>, Error> {
+        let mut shared = SHARED.lock().unwrap();
+        if let Some(connection) = shared.upgrade() {
+            return Ok(connection);
+        }
+        let connection = Arc::new(Connection::open(nitrokey::take_blocking)?);
+        *shared = Arc::downgrade(&connection);
+        Ok(connection)
+    }
+
+    /// Sends the job built by `job` and waits for its result.
+    fn call<T, F>(&self, job: F) -> Result<T, Error>
+    where
+        F: FnOnce(Sender<T>) -> Job,
+    {
+        let (tx, rx) = channel();
+        if let Some(jobs) = &self.jobs {
+            if jobs.send(job(tx)).is_ok() {
+                if let Ok(res) = rx.recv() {
+                    return Ok(res);
+                }
+            }
+        }
+        // the thread is gone
+        Err(CommunicationError::NotConnected.into())
+    }
+
+    pub(crate) fn devices(&self) -> Result<Vec<DeviceInfo>, Error> {
+        self.call(|tx| {
+            Job::Manager(Box::new(move |manager| {
+                let _ = tx.send(manager.devices());
+            }))
+        })?
+    }
+
+    /// Calls `f` with the device at `path`. Its password safe is closed
+    /// first, if it was open.
+    pub(crate) fn with_device<T, F>(&self, path: &str, f: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut dyn Device) -> Result<T, Error> + Send + 'static,
+    {
+        self.call(|tx| Job::Device {
+            path: path.into(),
+            run: Box::new(move |device| {
+                let _ = tx.send(device.and_then(f));
+            }),
+        })?
+    }
+
+    /// Calls `f` with the password safe of the device at `path`, unlocking it
+    /// with `pin` if it isn't open already. Returns `None` if the safe isn't
+    /// open and no pin was given.
+    pub(crate) fn with_safe<T, F>(
+        &self,
+        path: &str,
+        pin: Option<Zeroizing<String>>,
+        f: F,
+    ) -> Result<Option<T>, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut dyn Safe) -> Result<T, Error> + Send + 'static,
+    {
+        self.call(|tx| Job::Safe {
+            path: path.into(),
+            pin,
+            run: Box::new(move |safe| {
+                let res = match safe {
+                    Ok(Some(safe)) => f(safe).map(Some),
+                    Ok(None) => Ok(None),
+                    Err(err) => Err(err),
+                };
+                let _ = tx.send(res);
+            }),
+        })?
+    }
+
+    /// Closes the password safe of the device at `path`.
+    pub(crate) fn close_safe(&self, path: &str) -> Result<(), Error> {
+        self.with_device(path, |_| Ok(()))
+    }
+}
@@ -0,0 +1,390 @@
+//! Simulated Nitrokeys, which the tests and benchmarks use in place of real
+//! devices.
+
+use crate::nitrokey_device::{Connection, Device, Manager, Safe};
+use crate::{DeviceReport, NitrokeyBackend, Pin, PinRequest, PwvltError, Slot, SlotUsage};
+
+use nitrokey::{
+    CommandError, CommunicationError, DeviceInfo, Error, FirmwareVersion, LibraryError, Model,
+    SerialNumber,
+};
+
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+const SLOT_COUNT: u8 = 16;
+const RETRIES: u8 = 3;
+
+struct SimulatedSlot {
+    name: String,
+    login: String,
+    password: String,
+}
+
+/// The state of a simulated device, which tests can inspect.
+pub(crate) struct SimulatedDevice {
+    pub path: String,
+    pub serial: SerialNumber,
+    pub user_pin: String,
+    pub admin_pin: String,
+    pub user_retries: u8,
+    pub admin_retries: u8,
+    /// Whether the AES key of the password safe was built.
+    pub initialized: bool,
+    /// Whether the password safe is unlocked.
+    pub unlocked: bool,
+    slots: Vec<Option<SimulatedSlot>>,
+}
+
+impl SimulatedDevice {
+    fn check_user_pin(&mut self, pin: &str) -> Result<(), Error> {
+        if self.user_retries == 0 || pin != self.user_pin {
+            self.user_retries = self.user_retries.saturating_sub(1);
+            return Err(CommandError::WrongPassword.into());
+        }
+        self.user_retries = RETRIES;
+        Ok(())
+    }
+
+    fn check_admin_pin(&mut self, pin: &str) -> Result<(), Error> {
+        if self.admin_retries == 0 || pin != self.admin_pin {
+            self.admin_retries = self.admin_retries.saturating_sub(1);
+            return Err(CommandError::WrongPassword.into());
+        }
+        self.admin_retries = RETRIES;
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct State {
+    devices: Vec<SimulatedDevice>,
+    /// How many commands were sent to the devices.
+    commands: usize,
+    /// The slots whose name was read, by all the devices.
+    names_read: Vec<u8>,
+    /// How long each command takes.
+    latency: Duration,
+}
+
+impl State {
+    /// Counts a command sent to the device at `path`, and returns it.
+    fn command(&mut self, path: &str) -> Result<&mut SimulatedDevice, Error> {
+        self.commands += 1;
+        if !self.latency.is_zero() {
+            thread::sleep(self.latency);
+        }
+        self.devices
+            .iter_mut()
+            .find(|device| device.path == path)
+            .ok_or_else(|| CommunicationError::NotConnected.into())
+    }
+}
+
+struct SimulatedManager(Arc<Mutex<State>>);
+
+/// A device connected through a `SimulatedManager`, which it borrows like a
+/// real one.
+struct Connected<'m> {
+    state: &'m Mutex<State>,
+    path: String,
+}
+
+/// The password safe of a `Connected` device, which it borrows like a real
+/// one.
+struct OpenSafe<'d> {
+    device: &'d Connected<'d>,
+}
+
+impl Connected<'_> {
+    fn command<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&mut SimulatedDevice) -> Result<T, Error>,
+    {
+        f(self.state.lock().unwrap().command(&self.path)?)
+    }
+}
+
+impl Manager for SimulatedManager {
+    fn devices(&mut self) -> Result<Vec<DeviceInfo>, Error> {
+        let state = self.0.lock().unwrap();
+        Ok(state
+            .devices
+            .iter()
+            .map(|device| DeviceInfo {
+                model: Some(Model::Pro),
+                path: device.path.clone(),
+                serial_number: Some(device.serial),
+            })
+            .collect())
+    }
+
+    fn connect(&mut self, path: &str) -> Result<Box<dyn Device + '_>, Error> {
+        let connected = Connected {
+            state: &self.0,
+            path: path.into(),
+        };
+        connected.command(|_| Ok(()))?;
+        Ok(Box::new(connected))
+    }
+}
+
+impl Device for Connected<'_> {
+    fn user_retry_count(&self) -> Result<u8, Error> {
+        self.command(|device| Ok(device.user_retries))
+    }
+
+    fn admin_retry_count(&self) -> Result<u8, Error> {
+        self.command(|device| Ok(device.admin_retries))
+    }
+
+    fn report(&self) -> Result<DeviceReport, Error> {
+        self.command(|device| {
+            Ok(DeviceReport {
+                model: Model::Pro,
+                firmware: FirmwareVersion {
+                    major: 0,
+                    minor: 53,
+                },
+                serial: device.serial,
+                user_retries: device.user_retries,
+                admin_retries: device.admin_retries,
+                hotp_slots: SlotUsage {
+                    programmed: 0,
+                    total: 3,
+                },
+                totp_slots: SlotUsage {
+                    programmed: 0,
+                    total: 15,
+                },
+            })
+        })
+    }
+
+    fn password_safe(&mut self, pin: &str) -> Result<Box<dyn Safe + '_>, Error> {
+        self.command(|device| {
+            if !device.initialized {
+                return Err(CommandError::AesDecryptionFailed.into());
+            }
+            device.check_user_pin(pin)?;
+            device.unlocked = true;
+            Ok(())
+        })?;
+        Ok(Box::new(OpenSafe { device: self }))
+    }
+
+    fn change_user_pin(&mut self, current: &str, new: &str) -> Result<(), Error> {
+        self.command(|device| {
+            device.check_user_pin(current)?;
+            device.user_pin = new.into();
+            Ok(())
+        })
+    }
+
+    fn unlock_user_pin(&mut self, admin_pin: &str, user_pin: &str) -> Result<(), Error> {
+        self.command(|device| {
+            device.check_admin_pin(admin_pin)?;
+            device.user_pin = user_pin.into();
+            device.user_retries = RETRIES;
+            Ok(())
+        })
+    }
+
+    fn build_aes_key(&mut self, admin_pin: &str) -> Result<(), Error> {
+        self.command(|device| {
+            device.check_admin_pin(admin_pin)?;
+            device.slots = (0..SLOT_COUNT).map(|_| None).collect();
+            device.initialized = true;
+            device.unlocked = false;
+            Ok(())
+        })
+    }
+
+    fn lock(&mut self) -> Result<(), Error> {
+        self.command(|device| {
+            device.unlocked = false;
+            Ok(())
+        })
+    }
+}
+
+impl OpenSafe<'_> {
+    fn command<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&mut SimulatedDevice) -> Result<T, Error>,
+    {
+        self.device.command(|device| {
+            // e.g. another application locked the device
+            if !device.unlocked {
+                return Err(CommandError::NotAuthorized.into());
+            }
+            f(device)
+        })
+    }
+}
+
+impl Safe for OpenSafe<'_> {
+    fn slots(&self) -> Result<Vec<Option<Slot>>, Error> {
+        // the status of all the slots is a single command, like on a real
+        // device, and only the programmed slots are read
+        let programmed: Vec<bool> =
+            self.command(|device| Ok(device.slots.iter().map(Option::is_some).collect()))?;
+        let mut slots = Vec::with_capacity(programmed.len());
+        for (i, programmed) in programmed.into_iter().enumerate() {
+            if !programmed {
+                slots.push(None);
+                continue;
+            }
+            let service =
+                self.command(|device| Ok(device.slots[i].as_ref().unwrap().name.clone()))?;
+            let username =
+                self.command(|device| Ok(device.slots[i].as_ref().unwrap().login.clone()))?;
+            self.device.state.lock().unwrap().names_read.push(i as u8);
+            slots.push(Some(Slot { service, username }));
+        }
+        Ok(slots)
+    }
+
+    fn password(&self, slot: u8) -> Result<String, Error> {
+        self.command(|device| match device.slots.get(usize::from(slot)) {
+            Some(Some(slot)) => Ok(slot.password.clone()),
+            Some(None) => Err(CommandError::SlotNotProgrammed.into()),
+            None => Err(LibraryError::InvalidSlot.into()),
+        })
+    }
+
+    fn write_slot(
+        &mut self,
+        slot: u8,
+        name: &str,
+        login: &str,
+        password: &str,
+    ) -> Result<(), Error> {
+        self.command(|device| match device.slots.get_mut(usize::from(slot)) {
+            Some(slot) => {
+                slot.replace(SimulatedSlot {
+                    name: name.into(),
+                    login: login.into(),
+                    password: password.into(),
+                });
+                Ok(())
+            }
+            None => Err(LibraryError::InvalidSlot.into()),
+        })
+    }
+}
+
+/// Simulates Nitrokey Pros. Each device starts with the default pins and an
+/// initialized, empty password safe.
+pub struct NitrokeySimulator {
+    state: Arc<Mutex<State>>,
+    connection: Arc<Connection>,
+}
+
+impl Default for NitrokeySimulator {
+    fn default() -> NitrokeySimulator {
+        NitrokeySimulator::new()
+    }
+}
+
+impl NitrokeySimulator {
+    /// The pins of a new Nitrokey.
+    pub const USER_PIN: &'static str = "123456";
+    pub const ADMIN_PIN: &'static str = "12345678";
+
+    pub fn new() -> NitrokeySimulator {
+        let state = Arc::new(Mutex::new(State::default()));
+        let manager = SimulatedManager(Arc::clone(&state));
+        let connection =
+            Connection::open(move || Ok(manager)).expect("The simulated connection can't fail.");
+        NitrokeySimulator {
+            state,
+            connection: Arc::new(connection),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    /// Adds a device with the given serial number (e.g. `0x0001b2c3`).
+    pub fn add_device(&self, serial: &str) -> SerialNumber {
+        let serial: SerialNumber = serial.parse().expect("Invalid serial number");
+        let mut state = self.state();
+        let path = format!("sim:{}", state.devices.len());
+        state.devices.push(SimulatedDevice {
+            path,
+            serial,
+            user_pin: NitrokeySimulator::USER_PIN.into(),
+            admin_pin: NitrokeySimulator::ADMIN_PIN.into(),
+            user_retries: RETRIES,
+            admin_retries: RETRIES,
+            initialized: true,
+            unlocked: false,
+            slots: (0..SLOT_COUNT).map(|_| None).collect(),
+        });
+        serial
+    }
+
+    /// Calls `f` with the state of the device with the given serial number.
+    pub(crate) fn with_device<T, F>(&self, serial: SerialNumber, f: F) -> T
+    where
+        F: FnOnce(&mut SimulatedDevice) -> T,
+    {
+        let mut state = self.state();
+        let device = state
+            .devices
+            .iter_mut()
+            .find(|device| device.serial == serial)
+            .expect("Unknown serial number");
+        f(device)
+    }
+
+    /// Programs a slot directly, without unlocking the password safe.
+    pub fn write_slot(
+        &self,
+        serial: SerialNumber,
+        slot: u8,
+        name: &str,
+        login: &str,
+        password: &str,
+    ) {
+        self.with_device(serial, |device| {
+            device.slots[usize::from(slot)] = Some(SimulatedSlot {
+                name: name.into(),
+                login: login.into(),
+                password: password.into(),
+            });
+        })
+    }
+
+    /// Makes every command take `latency`, like a round trip over USB.
+    pub fn set_latency(&self, latency: Duration) {
+        self.state().latency = latency;
+    }
+
+    /// Returns how many commands were sent to the devices so far.
+    pub fn commands(&self) -> usize {
+        self.state().commands
+    }
+
+    /// Returns the slots whose name was read so far, in order.
+    #[cfg(test)]
+    pub(crate) fn names_read(&self) -> Vec<u8> {
+        self.state().names_read.clone()
+    }
+
+    /// Creates a backend for the device with the given serial number (or the
+    /// first one, if `serial` is `None`).
+    pub fn backend<F>(
+        &self,
+        serial: Option<SerialNumber>,
+        unlock_hook: F,
+    ) -> Result<NitrokeyBackend, PwvltError>
+    where
+        F: Fn(PinRequest) -> Result<Pin, PwvltError> + Send + Sync + 'static,
+    {
+        NitrokeyBackend::with_connection(Arc::clone(&self.connection), serial, unlock_hook)
+    }
+}
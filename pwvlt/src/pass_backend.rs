@@ -0,0 +1,439 @@
+use crate::config::Pass;
+use crate::{Backend, PwvltError, Slot};
+
+use zeroize::Zeroizing;
+
+use std::env;
+use std::fs::{self, read_dir, read_to_string, remove_dir, remove_file, rename};
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Stores passwords in a `pass` password store. Each password is a file
+/// named `<service>/<username>.gpg`, encrypted to the keys listed in the
+/// nearest `.gpg-id`. Like `pass`, the first line of the decrypted file is
+/// the password and the remaining lines are left alone.
+pub struct PassBackend {
+    store: PathBuf,
+    gpg: String,
+}
+
+/// Rejects services and usernames which would escape their place in the
+/// store. Services can contain slashes, as `pass` allows nested folders.
+fn check_name(field: &'static str, value: &str) -> Result<(), PwvltError> {
+    if field == "username" && value.contains('/') {
+        return Err(PwvltError::InvalidCharacter {
+            field,
+            character: '/',
+        });
+    }
+    // hidden folders and files (e.g. `.git`) aren't entries
+    let valid = Path::new(value)
+        .components()
+        .all(|component| match component {
+            Component::Normal(name) => !name.to_string_lossy().starts_with('.'),
+            _ => false,
+        });
+    if value.is_empty() || value.ends_with('/') || !valid {
+        return Err(PwvltError::Pass(format!("Invalid {} {:?}.", field, value)));
+    }
+    Ok(())
+}
+
+impl PassBackend {
+    pub fn new() -> Result<PassBackend, PwvltError> {
+        PassBackend::with_config(Default::default())
+    }
+
+    /// Uses the configured store, then `$PASSWORD_STORE_DIR`, then
+    /// `~/.password-store`.
+    pub fn with_config(config: Pass) -> Result<PassBackend, PwvltError> {
+        let store = match config.store {
+            Some(store) => store,
+            None => match env::var_os("PASSWORD_STORE_DIR") {
+                Some(store) => PathBuf::from(store),
+                None => home::home_dir()
+                    .ok_or_else(|| PwvltError::Pass("Can't find the home directory.".into()))?
+                    .join(".password-store"),
+            },
+        };
+        if !store.join(".gpg-id").is_file() {
+            return Err(PwvltError::Pass(format!(
+                "{} isn't a password store, run `pass init` first.",
+                store.display()
+            )));
+        }
+        Ok(PassBackend {
+            store,
+            gpg: config.gpg,
+        })
+    }
+
+    fn entry_path(&self, service: &str, username: &str) -> PathBuf {
+        self.store.join(service).join(format!("{}.gpg", username))
+    }
+
+    /// Returns the keys listed in the `.gpg-id` closest to `dir`, looking up
+    /// to the root of the store.
+    fn recipients(&self, dir: &Path) -> Result<Vec<String>, PwvltError> {
+        let gpg_id = dir
+            .ancestors()
+            .take_while(|dir| dir.starts_with(&self.store))
+            .map(|dir| dir.join(".gpg-id"))
+            .find(|gpg_id| gpg_id.is_file())
+            .ok_or_else(|| PwvltError::Pass("The store has no .gpg-id.".into()))?;
+        Ok(read_to_string(gpg_id)?
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or_default().trim())
+            .filter(|id| !id.is_empty())
+            .map(String::from)
+            .collect())
+    }
+
+    /// Runs gpg with `args`, feeding it `input` and returning what it
+    /// printed.
+    fn gpg(&self, args: &[&str], input: &[u8]) -> Result<Zeroizing<Vec<u8>>, PwvltError> {
+        let mut child = Command::new(&self.gpg)
+            .args([
+                "--quiet",
+                "--yes",
+                "--compress-algo=none",
+                "--no-encrypt-to",
+            ])
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|err| PwvltError::Pass(format!("Failed to run {}: {}", self.gpg, err)))?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(input)?;
+        }
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            return Err(PwvltError::Pass(format!(
+                "{} failed: {}",
+                self.gpg,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(Zeroizing::new(output.stdout))
+    }
+
+    fn decrypt(&self, path: &Path) -> Result<Zeroizing<String>, PwvltError> {
+        let plaintext = self.gpg(&["--decrypt", &path.to_string_lossy()], &[])?;
+        Ok(Zeroizing::new(String::from_utf8(plaintext.to_vec())?))
+    }
+
+    /// Encrypts `plaintext` into `path`. The file is replaced in one go, so
+    /// a failure leaves the previous entry intact.
+    fn encrypt(&self, path: &Path, plaintext: &str) -> Result<(), PwvltError> {
+        let dir = path.parent().unwrap_or(&self.store);
+        fs::create_dir_all(dir)?;
+        let mut args = vec!["--encrypt".to_string()];
+        for recipient in self.recipients(dir)? {
+            args.push("--recipient".into());
+            args.push(recipient);
+        }
+        let tmp = path.with_extension("gpg.tmp");
+        args.push("--output".into());
+        args.push(tmp.to_string_lossy().into_owned());
+        let args: Vec<_> = args.iter().map(String::as_str).collect();
+        if let Err(err) = self.gpg(&args, plaintext.as_bytes()) {
+            let _ = remove_file(&tmp);
+            return Err(err);
+        }
+        rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// Deletes an entry, and the folders it leaves empty.
+    fn remove(&self, service: &str, username: &str) -> Result<(), PwvltError> {
+        let path = self.entry_path(service, username);
+        remove_file(&path)?;
+        for dir in path.ancestors().skip(1) {
+            if dir == self.store || remove_dir(dir).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Lists the entries under `dir`, skipping hidden files and folders
+    /// (e.g. `.git`).
+    fn entries(&self, dir: &Path, entries: &mut Vec<Slot>) -> Result<(), PwvltError> {
+        for file in read_dir(dir)? {
+            let path = file?.path();
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            if name.starts_with('.') {
+                continue;
+            }
+            if path.is_dir() {
+                self.entries(&path, entries)?;
+            } else if let (Some(username), Ok(service)) =
+                (name.strip_suffix(".gpg"), dir.strip_prefix(&self.store))
+            {
+                // entries at the root of the store have no service
+                if service.as_os_str().is_empty() {
+                    continue;
+                }
+                entries.push(Slot {
+                    service: service.to_string_lossy().into(),
+                    username: username.into(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the entries of the store, sorted by service and username.
+    fn sorted_entries(&self) -> Result<Vec<Slot>, PwvltError> {
+        let mut entries = Vec::new();
+        self.entries(&self.store, &mut entries)?;
+        entries.sort_by(|a, b| (&a.service, &a.username).cmp(&(&b.service, &b.username)));
+        Ok(entries)
+    }
+}
+
+impl Backend for PassBackend {
+    fn password(&self, service: &str, username: &str) -> Result<String, PwvltError> {
+        check_name("service", service)?;
+        check_name("username", username)?;
+        let path = self.entry_path(service, username);
+        if !path.is_file() {
+            return Err(PwvltError::PasswordNotFound);
+        }
+        let plaintext = self.decrypt(&path)?;
+        Ok(plaintext.lines().next().unwrap_or_default().into())
+    }
+
+    fn set_password(
+        &self,
+        slot: usize,
+        service: &str,
+        username: &str,
+        password: &str,
+    ) -> Result<(), PwvltError> {
+        check_name("service", service)?;
+        check_name("username", username)?;
+        if password.contains('\n') {
+            return Err(PwvltError::InvalidCharacter {
+                field: "password",
+                character: '\n',
+            });
+        }
+        let old = self.sorted_entries()?.into_iter().nth(slot);
+        let path = self.entry_path(service, username);
+        // keep the lines after the password, e.g. a URL or notes
+        let plaintext = if path.is_file() {
+            let old = self.decrypt(&path)?;
+            let rest = old
+                .split_once('\n')
+                .map(|(_, rest)| rest)
+                .unwrap_or_default();
+            Zeroizing::new(format!("{}\n{}", password, rest))
+        } else {
+            Zeroizing::new(format!("{}\n", password))
+        };
+        self.encrypt(&path, &plaintext)?;
+        if let Some(old) = old {
+            if old.service != service || old.username != username {
+                self.remove(&old.service, &old.username)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        "pass"
+    }
+
+    fn slots(&self) -> Result<Vec<Slot>, PwvltError> {
+        let mut slots = self.sorted_entries()?;
+        slots.push(Default::default());
+        Ok(slots)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::temp_dir::TempDir;
+
+    use std::os::unix::fs::PermissionsExt;
+
+    /// Stands in for gpg: "encrypted" files are the plaintext, preceded by a
+    /// line listing the recipients.
+    const FAKE_GPG: &str = r#"#!/bin/sh
+mode=; file=; output=; recipients=
+while [ $# -gt 0 ]; do
+    case "$1" in
+        --encrypt) mode=encrypt ;;
+        --decrypt) mode=decrypt; shift; file="$1" ;;
+        --recipient) shift; recipients="$recipients $1" ;;
+        --output) shift; output="$1" ;;
+    esac
+    shift
+done
+if [ "$mode" = encrypt ]; then
+    { echo "recipients:$recipients"; cat; } > "$output"
+else
+    tail -n +2 "$file"
+fi
+"#;
+
+    /// A password store in a temporary folder, removed when dropped.
+    struct Store {
+        backend: PassBackend,
+        _dir: TempDir,
+    }
+
+    impl Store {
+        fn new() -> Store {
+            let dir = TempDir::new("pwvlt-pass");
+            let store = dir.path().join("store");
+            fs::create_dir_all(&store).unwrap();
+            fs::write(store.join(".gpg-id"), "root@example.com\n").unwrap();
+            let gpg = dir.path().join("gpg");
+            fs::write(&gpg, FAKE_GPG).unwrap();
+            fs::set_permissions(&gpg, fs::Permissions::from_mode(0o755)).unwrap();
+            let config = Pass {
+                store: Some(store),
+                gpg: gpg.to_string_lossy().into(),
+            };
+            let backend = PassBackend::with_config(config).unwrap();
+            Store { backend, _dir: dir }
+        }
+
+        fn path(&self, path: &str) -> PathBuf {
+            self.backend.store.join(path)
+        }
+
+        fn read(&self, path: &str) -> String {
+            read_to_string(self.path(path)).unwrap()
+        }
+
+        fn write(&self, path: &str, contents: &str) {
+            let path = self.path(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+    }
+
+    fn is_invalid(res: Result<(), PwvltError>) -> bool {
+        matches!(res, Err(PwvltError::Pass(_)))
+    }
+
+    #[test]
+    fn names_which_escape_the_store_are_rejected() {
+        assert!(check_name("service", "github").is_ok());
+        assert!(check_name("service", "work/github").is_ok());
+        assert!(is_invalid(check_name("service", "..")));
+        assert!(is_invalid(check_name("service", "work/../..")));
+        assert!(is_invalid(check_name("service", "/etc")));
+        assert!(is_invalid(check_name("service", ".git")));
+        assert!(is_invalid(check_name("service", "work/.hidden")));
+        assert!(is_invalid(check_name("service", "work/")));
+        assert!(is_invalid(check_name("service", "")));
+        assert!(is_invalid(check_name("username", "..")));
+        assert!(is_invalid(check_name("username", ".alice")));
+        assert!(matches!(
+            check_name("username", "alice/bob"),
+            Err(PwvltError::InvalidCharacter { character: '/', .. })
+        ));
+    }
+
+    #[test]
+    fn the_nearest_gpg_id_is_used() {
+        let store = Store::new();
+        store.write(
+            "work/.gpg-id",
+            "# the team\nalice@example.com # Alice\n\nbob@example.com\n",
+        );
+
+        store
+            .backend
+            .set_password(0, "work/mail", "carol", "secret")
+            .unwrap();
+        assert_eq!(
+            store.read("work/mail/carol.gpg"),
+            "recipients: alice@example.com bob@example.com\nsecret\n"
+        );
+        store
+            .backend
+            .set_password(1, "home", "carol", "secret")
+            .unwrap();
+        assert_eq!(
+            store.read("home/carol.gpg"),
+            "recipients: root@example.com\nsecret\n"
+        );
+        assert_eq!(
+            store.backend.password("work/mail", "carol").unwrap(),
+            "secret"
+        );
+    }
+
+    #[test]
+    fn overwriting_keeps_the_other_lines() {
+        let store = Store::new();
+        store.write(
+            "github/alice.gpg",
+            "recipients: root@example.com\nold\nurl: github.com\nnotes\n",
+        );
+
+        store
+            .backend
+            .set_password(0, "github", "alice", "new")
+            .unwrap();
+        assert_eq!(
+            store.read("github/alice.gpg"),
+            "recipients: root@example.com\nnew\nurl: github.com\nnotes\n"
+        );
+        assert_eq!(store.backend.password("github", "alice").unwrap(), "new");
+    }
+
+    #[test]
+    fn writing_to_a_slot_replaces_its_entry() {
+        let store = Store::new();
+        store
+            .backend
+            .set_password(0, "work/old", "alice", "old")
+            .unwrap();
+
+        store.backend.set_password(0, "new", "bob", "new").unwrap();
+        // the folders left empty are removed, but not the store
+        assert!(!store.path("work").exists());
+        assert!(store.path(".gpg-id").is_file());
+        let slots = store.backend.slots().unwrap();
+        assert_eq!(slots.len(), 2);
+        assert_eq!(
+            (slots[0].service.as_str(), slots[0].username.as_str()),
+            ("new", "bob")
+        );
+        assert!(matches!(
+            store.backend.password("work/old", "alice"),
+            Err(PwvltError::PasswordNotFound)
+        ));
+    }
+
+    #[test]
+    fn hidden_files_are_not_slots() {
+        let store = Store::new();
+        store.write(".git/objects/ab.gpg", "");
+        store.write(".git/HEAD", "ref: refs/heads/main\n");
+        store.write("github/.alice.gpg", "");
+        store.write("github/bob.gpg", "recipients: root@example.com\nsecret\n");
+        store.write("root.gpg", "");
+
+        let slots = store.backend.slots().unwrap();
+        let slots: Vec<_> = slots
+            .iter()
+            .map(|slot| (slot.service.as_str(), slot.username.as_str()))
+            .collect();
+        assert_eq!(
+            slots,
+            [("github", "bob"), ("<not programmed>", "<not programmed>")]
+        );
+    }
+}
@@ -0,0 +1,37 @@
+//! Temporary folders for the tests of the file-based backends.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static DIR_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// A folder created in the system's temporary folder, and removed with its
+/// contents when dropped. Tests run in parallel, so each one gets its own.
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Creates a folder whose name starts with `prefix`, e.g. `pwvlt-pass`.
+    pub fn new(prefix: &str) -> TempDir {
+        let path = env::temp_dir().join(format!(
+            "{}-{}-{}",
+            prefix,
+            process::id(),
+            DIR_COUNT.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
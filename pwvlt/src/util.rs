@@ -2,16 +2,149 @@ use crate::{config, PwvltError};
 
 use passwords::PasswordGenerator;
 
-pub fn random_password(config: &config::Password) -> Result<String, PwvltError> {
-    let pg = PasswordGenerator {
+/// The number of characters `PasswordGenerator` picks from for each
+/// character class (similar looking characters are left out).
+const NUMBERS: usize = 9;
+const LOWERCASE_LETTERS: usize = 23;
+const UPPERCASE_LETTERS: usize = 23;
+const SYMBOLS: usize = 28;
+
+fn generator(config: &config::Password) -> PasswordGenerator {
+    PasswordGenerator {
         length: config.length,
         numbers: config.numbers,
         lowercase_letters: config.lowercase_letters,
         uppercase_letters: config.uppercase_letters,
         symbols: config.symbols,
         strict: config.strict,
-    };
+    }
+}
+
+pub fn random_password(config: &config::Password) -> Result<String, PwvltError> {
+    config.validate()?;
     log::info!("Generating random password.");
-    pg.generate_one()
+    generator(config)
+        .generate_one()
         .map_err(|e| PwvltError::PasswordGeneration(e.into()))
 }
+
+/// Generates `count` random passwords using the given policy.
+pub fn random_passwords(
+    config: &config::Password,
+    count: usize,
+) -> Result<Vec<String>, PwvltError> {
+    config.validate()?;
+    log::info!("Generating {} random passwords.", count);
+    let generator = generator(config);
+    // `PasswordGenerator::generate` doesn't always return `count` passwords
+    (0..count)
+        .map(|_| {
+            generator
+                .generate_one()
+                .map_err(|e| PwvltError::PasswordGeneration(e.into()))
+        })
+        .collect()
+}
+
+/// Estimates the entropy (in bits) of a password generated using the given
+/// policy. Strict mode slightly lowers the real value, since every enabled
+/// character class must appear at least once.
+pub fn entropy_bits(config: &config::Password) -> f64 {
+    let alphabet = [
+        (config.numbers, NUMBERS),
+        (config.lowercase_letters, LOWERCASE_LETTERS),
+        (config.uppercase_letters, UPPERCASE_LETTERS),
+        (config.symbols, SYMBOLS),
+    ]
+    .iter()
+    .filter(|(enabled, _)| *enabled)
+    .map(|(_, size)| size)
+    .sum::<usize>();
+    if alphabet == 0 {
+        return 0.0;
+    }
+    config.length as f64 * (alphabet as f64).log2()
+}
+
+/// Checks whether `text` matches `pattern`, where `*` matches any sequence of
+/// characters (including an empty one).
+pub fn matches_pattern(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    // there is always at least one part
+    let first = parts.next().unwrap_or("");
+    let mut rest = match text.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let parts: Vec<_> = parts.collect();
+    let (last, middle) = match parts.split_last() {
+        Some(split) => split,
+        // the pattern doesn't contain any `*`
+        None => return rest.is_empty(),
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn random_passwords_returns_count_passwords() {
+        let config = config::Password {
+            length: 8,
+            numbers: true,
+            lowercase_letters: true,
+            ..Default::default()
+        };
+        // `PasswordGenerator::generate` returns fewer passwords once `count`
+        // exceeds the length
+        for count in [1, 5, 9, 24] {
+            let passwords = random_passwords(&config, count).unwrap();
+            assert_eq!(passwords.len(), count);
+            assert!(passwords.iter().all(|p| p.len() == config.length));
+        }
+    }
+
+    #[test]
+    fn patterns_without_stars_match_exactly() {
+        assert!(matches_pattern("github", "github"));
+        assert!(!matches_pattern("github", "github.com"));
+        assert!(!matches_pattern("github", "git"));
+        assert!(matches_pattern("", ""));
+        assert!(!matches_pattern("", "github"));
+    }
+
+    #[test]
+    fn stars_match_any_sequence() {
+        // at the start
+        assert!(matches_pattern("*.com", "github.com"));
+        assert!(matches_pattern("*.com", ".com"));
+        assert!(!matches_pattern("*.com", "github.org"));
+        // in the middle
+        assert!(matches_pattern("git*.com", "github.com"));
+        assert!(matches_pattern("git*.com", "git.com"));
+        assert!(!matches_pattern("git*.com", "gitlab.org"));
+        // at the end
+        assert!(matches_pattern("work-*", "work-mail"));
+        assert!(matches_pattern("work-*", "work-"));
+        assert!(!matches_pattern("work-*", "home-mail"));
+        // several
+        assert!(matches_pattern("*", ""));
+        assert!(matches_pattern("*a*b*", "xaybz"));
+        assert!(!matches_pattern("*a*b*", "xbya"));
+    }
+
+    #[test]
+    fn prefix_and_suffix_dont_overlap() {
+        assert!(!matches_pattern("ab*ba", "aba"));
+        assert!(matches_pattern("ab*ba", "abba"));
+        assert!(matches_pattern("ab*ba", "abxba"));
+    }
+}
@@ -1,61 +1,333 @@
-use crate::config::{BackendName, Config};
+use crate::config::{BackendName, Config, LookupStrategy};
+use crate::lazy_backend::{Connect, LazyBackend};
 use crate::util::random_password;
-use crate::{Backend, KeyringBackend, NitrokeyBackend, PwvltError};
+use crate::{
+    Attempt, Backend, Event, KWalletBackend, KdbxBackend, KdbxUnlock, KeyringBackend, LogObserver,
+    NitrokeyBackend, Observer, PassBackend, Pin, PinRequest, PwvltError, SerialNumber,
+};
 
-#[derive(Default)]
-/// The PasswordVault deals with managing multiple password backends.
+use std::path::Path;
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+
+/// Shared by the backends of a vault which may prompt the user to unlock
+/// them. Backends can be queried in parallel, and their prompts would mix on
+/// the terminal otherwise.
+#[derive(Clone, Default)]
+pub(crate) struct PromptLock(Arc<Mutex<()>>);
+
+impl PromptLock {
+    pub(crate) fn acquire(&self) -> MutexGuard<'_, ()> {
+        // a hook which panicked doesn't leave anything to clean up
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// The PasswordVault deals with managing multiple password backends. It can be
+/// shared between threads (e.g. behind an `Arc`).
 pub struct PasswordVault {
     config: Config,
-    backends: Vec<Box<dyn Backend>>,
+    backends: Vec<Arc<dyn Backend>>,
+    /// The kind of each backend, and the serial number of the Nitrokeys.
+    targets: Vec<(BackendName, Option<SerialNumber>)>,
+    observer: Arc<dyn Observer>,
 }
 
+impl Default for PasswordVault {
+    fn default() -> PasswordVault {
+        PasswordVault {
+            config: Default::default(),
+            backends: Vec::new(),
+            targets: Vec::new(),
+            observer: Arc::new(LogObserver),
+        }
+    }
+}
+
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<PasswordVault>();
+};
+
 impl PasswordVault {
+    /// Creates a vault which reports its events using the `log` crate.
+    /// `kdbx_unlock` is given the path of the KeePass database and returns its
+    /// master password.
     pub fn new(
         config: Config,
-        nitrokey_unlock: Option<fn() -> Result<String, PwvltError>>,
+        nitrokey_unlock: Option<fn(PinRequest) -> Result<Pin, PwvltError>>,
+        kdbx_unlock: Option<KdbxUnlock>,
+    ) -> PasswordVault {
+        PasswordVault::with_observer(config, nitrokey_unlock, kdbx_unlock, Arc::new(LogObserver))
+    }
+
+    pub fn with_observer(
+        config: Config,
+        nitrokey_unlock: Option<fn(PinRequest) -> Result<Pin, PwvltError>>,
+        kdbx_unlock: Option<KdbxUnlock>,
+        observer: Arc<dyn Observer>,
     ) -> PasswordVault {
-        let mut backends: Vec<Box<dyn Backend>> = Vec::with_capacity(2);
-        for backend in &config.general.backends {
+        let mut targets = Vec::with_capacity(2);
+        for &backend in &config.general.backends {
             match backend {
+                BackendName::Nitrokey => targets.extend(
+                    config
+                        .nitrokey_serials()
+                        .into_iter()
+                        .map(|serial| (backend, serial)),
+                ),
+                BackendName::Keyring
+                | BackendName::KWallet
+                | BackendName::Pass
+                | BackendName::Kdbx => targets.push((backend, None)),
+            }
+        }
+        let prompt = PromptLock::default();
+        let mut backends: Vec<Arc<dyn Backend>> = Vec::with_capacity(targets.len());
+        // backends are only connected to the first time they are used
+        for &(backend, serial) in &targets {
+            let (name, connect): (_, Connect) = match backend {
                 BackendName::Nitrokey => {
                     let nitrokey_unlock = nitrokey_unlock
                         .expect("Must provide an unlock hook if you use the Nitrokey backend.");
-                    match NitrokeyBackend::new(nitrokey_unlock) {
-                        Ok(nk) => {
-                            log::info!("Nitrokey backend loaded successfully!");
-                            backends.push(Box::new(nk))
-                        }
-                        Err(e) => log::warn!("Failed to access Nitrokey: {}", e),
-                    }
+                    let name = NitrokeyBackend::display_name(serial);
+                    let observer = Arc::clone(&observer);
+                    let backend_name = name.clone();
+                    let prompt = prompt.clone();
+                    let connect = move || {
+                        let observer = Arc::clone(&observer);
+                        let name = backend_name.clone();
+                        let prompt = prompt.clone();
+                        let unlock = move |request: PinRequest| {
+                            let _prompt = prompt.acquire();
+                            observer.notify(&Event::PinRequested {
+                                backend: &name,
+                                retries: request.retries,
+                            });
+                            nitrokey_unlock(request)
+                        };
+                        let nk = NitrokeyBackend::with_serial(serial, unlock)?;
+                        Ok(Box::new(nk) as Box<dyn Backend>)
+                    };
+                    (name, Box::new(connect))
                 }
-                BackendName::Keyring => match KeyringBackend::new() {
-                    Ok(kr) => {
-                        log::info!("Keyring backend loaded successfully!");
-                        backends.push(Box::new(kr))
-                    }
-                    Err(e) => log::warn!("Failed to access Keyring: {}", e),
-                },
-            }
+                BackendName::Keyring => {
+                    let keyring = config.keyring.clone();
+                    let prompt = prompt.clone();
+                    let connect = move || {
+                        let backend = KeyringBackend::with_config(keyring.clone())?
+                            .with_prompt_lock(prompt.clone());
+                        Ok(Box::new(backend) as Box<dyn Backend>)
+                    };
+                    ("Keyring".to_string(), Box::new(connect))
+                }
+                BackendName::KWallet => {
+                    let connect = || Ok(Box::new(KWalletBackend::new()?) as Box<dyn Backend>);
+                    ("KWallet".to_string(), Box::new(connect))
+                }
+                BackendName::Pass => {
+                    let pass = config.pass.clone();
+                    let connect = move || {
+                        let backend = PassBackend::with_config(pass.clone())?;
+                        Ok(Box::new(backend) as Box<dyn Backend>)
+                    };
+                    ("pass".to_string(), Box::new(connect))
+                }
+                BackendName::Kdbx => {
+                    let kdbx = config.kdbx.clone();
+                    // the hook is only called for databases with a password
+                    let kdbx_unlock: KdbxUnlock = match kdbx_unlock {
+                        Some(kdbx_unlock) => kdbx_unlock,
+                        None if !kdbx.password => |_| Err(PwvltError::PinCancelled),
+                        None => panic!(
+                            "Must provide an unlock hook if the KeePass database has a password."
+                        ),
+                    };
+                    let prompt = prompt.clone();
+                    let connect = move || {
+                        let prompt = prompt.clone();
+                        let unlock = move |path: &Path| {
+                            let _prompt = prompt.acquire();
+                            kdbx_unlock(path)
+                        };
+                        let backend = KdbxBackend::new(kdbx.clone(), unlock)?;
+                        Ok(Box::new(backend) as Box<dyn Backend>)
+                    };
+                    ("KeePass".to_string(), Box::new(connect))
+                }
+            };
+            backends.push(Arc::new(LazyBackend::new(
+                name,
+                connect,
+                config.timeouts(backend),
+                Arc::clone(&observer),
+            )));
+        }
+        PasswordVault {
+            backends,
+            targets,
+            config,
+            observer,
         }
-        PasswordVault { backends, config }
     }
 
-    pub fn backends(&self) -> &Vec<Box<dyn Backend>> {
+    pub fn backends(&self) -> &Vec<Arc<dyn Backend>> {
         &self.backends
     }
 
+    /// Looks for the password in each backend, according to the configured
+    /// lookup strategy. If none of them have it, the reason why each backend
+    /// failed is returned.
     pub fn password(&self, service: &str, username: &str) -> Result<String, PwvltError> {
-        for store in &self.backends {
-            let res = store.password(service, username);
-            log::info!("Looking for password in {}.", store.name());
-            if let Err(err) = res {
-                store.log_error(err);
-            } else {
-                log::info!("Found password in {}.", store.name());
+        match self.config.general.lookup {
+            LookupStrategy::Sequential => self.password_sequential(service, username),
+            LookupStrategy::ParallelFirstHit => self.password_parallel(service, username, true),
+            LookupStrategy::ParallelAll => self.password_parallel(service, username, false),
+        }
+    }
+
+    /// Returns the backend `service` should be written to, if it is routed
+    /// to one.
+    pub fn route(&self, service: &str) -> Option<usize> {
+        let route = *self.config.routes(service).first()?;
+        self.targets
+            .iter()
+            .position(|&(backend, serial)| route.matches(backend, serial))
+    }
+
+    /// Returns the indices of the backends, in the order in which they should
+    /// be queried for `service`. Backends matched by a route come first.
+    fn lookup_order(&self, service: &str) -> Vec<usize> {
+        let mut order = Vec::with_capacity(self.targets.len());
+        let routes = self.config.routes(service);
+        let routed = routes.iter().flat_map(|route| {
+            self.targets
+                .iter()
+                .enumerate()
+                .filter(move |(_, &(backend, serial))| route.matches(backend, serial))
+                .map(|(i, _)| i)
+        });
+        for i in routed.chain(0..self.targets.len()) {
+            if !order.contains(&i) {
+                order.push(i);
+            }
+        }
+        order
+    }
+
+    fn password_sequential(&self, service: &str, username: &str) -> Result<String, PwvltError> {
+        let mut attempts = Vec::with_capacity(self.backends.len());
+        for i in self.lookup_order(service) {
+            let store = &self.backends[i];
+            let backend = store.name();
+            self.observer.notify(&Event::LookupStarted {
+                backend,
+                service,
+                username,
+            });
+            match store.password(service, username) {
+                Ok(password) => {
+                    self.observer.notify(&Event::PasswordFound {
+                        backend,
+                        service,
+                        username,
+                    });
+                    return Ok(password);
+                }
+                Err(error) => {
+                    self.observer.notify(&Event::LookupMiss {
+                        backend,
+                        service,
+                        username,
+                        error: &error,
+                    });
+                    attempts.push(Attempt {
+                        backend: store.name().into(),
+                        error,
+                    });
+                }
+            }
+        }
+        Err(PwvltError::NotFoundAnywhere { attempts })
+    }
+
+    /// Queries all the backends at the same time. If `first_hit` is set, the
+    /// first password found is returned. Otherwise, all the backends are
+    /// waited for and the password of the first backend (in lookup order) is
+    /// returned, reporting a `LookupConflict` if the backends
+    /// disagree. Backends which prompt the user to unlock them take turns.
+    fn password_parallel(
+        &self,
+        service: &str,
+        username: &str,
+        first_hit: bool,
+    ) -> Result<String, PwvltError> {
+        let order = self.lookup_order(service);
+        let (tx, rx) = channel();
+        for &i in &order {
+            let store = &self.backends[i];
+            self.observer.notify(&Event::LookupStarted {
+                backend: store.name(),
+                service,
+                username,
+            });
+            let (store, tx) = (Arc::clone(store), tx.clone());
+            let (service, username) = (service.to_string(), username.to_string());
+            thread::spawn(move || {
+                // the receiver is gone if another backend was faster
+                let _ = tx.send((i, store.password(&service, &username)));
+            });
+        }
+        drop(tx);
+
+        let mut results = Vec::with_capacity(self.backends.len());
+        for (i, res) in rx {
+            let backend = self.backends[i].name();
+            match &res {
+                Ok(_) => self.observer.notify(&Event::PasswordFound {
+                    backend,
+                    service,
+                    username,
+                }),
+                Err(error) => self.observer.notify(&Event::LookupMiss {
+                    backend,
+                    service,
+                    username,
+                    error,
+                }),
+            }
+            if first_hit && res.is_ok() {
                 return res;
             }
+            results.push((i, res));
+        }
+        results.sort_by_key(|(i, _)| order.iter().position(|j| j == i));
+
+        let mut found = Vec::new();
+        let mut attempts = Vec::new();
+        for (i, res) in results {
+            let backend = self.backends[i].name();
+            match res {
+                Ok(password) => found.push((backend, password)),
+                Err(error) => attempts.push(Attempt {
+                    backend: backend.into(),
+                    error,
+                }),
+            }
         }
-        Err(PwvltError::PasswordNotFound)
+        if found.is_empty() {
+            return Err(PwvltError::NotFoundAnywhere { attempts });
+        }
+        if found.iter().any(|(_, password)| *password != found[0].1) {
+            let backends: Vec<_> = found.iter().map(|(backend, _)| *backend).collect();
+            self.observer.notify(&Event::LookupConflict {
+                service,
+                username,
+                backends: &backends,
+            });
+        }
+        Ok(found.swap_remove(0).1)
     }
 
     pub fn set_password(
@@ -67,15 +339,241 @@ impl PasswordVault {
         password: Option<&str>,
     ) -> Result<(), PwvltError> {
         let backend = &self.backends[backend];
-        backend.set_password(
-            slot,
+        let random;
+        let password = match password {
+            Some(password) => password,
+            None => {
+                let mut policy = self.config.password.clone();
+                if let Some(max) = backend
+                    .max_password_length()
+                    .filter(|&max| max < policy.length)
+                {
+                    log::info!(
+                        "Generating a {} character password, the longest {} can store.",
+                        max,
+                        backend.name()
+                    );
+                    policy.length = max;
+                }
+                random = random_password(&policy)?;
+                &random
+            }
+        };
+        backend
+            .set_password(slot, service, username, password)
+            .map_err(|err| PwvltError::Backend {
+                backend: backend.name().into(),
+                source: Box::new(err),
+            })?;
+        self.observer.notify(&Event::PasswordWritten {
+            backend: backend.name(),
             service,
             username,
-            password.unwrap_or(&random_password(&self.config.password)?),
-        )
+        });
+        Ok(())
+    }
+
+    /// Copies the password of `username` on `service` from one backend to
+    /// slot `slot` of another, e.g. to make room in a full backend.
+    pub fn copy_password(
+        &self,
+        from: usize,
+        to: usize,
+        slot: usize,
+        service: &str,
+        username: &str,
+    ) -> Result<(), PwvltError> {
+        let source = &self.backends[from];
+        let password = source
+            .password(service, username)
+            .map_err(|err| PwvltError::Backend {
+                backend: source.name().into(),
+                source: Box::new(err),
+            })?;
+        self.set_password(to, slot, service, username, Some(&password))
     }
 
     pub fn default(&self, service: &str) -> Option<&String> {
         self.config.default.get(service)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Slot;
+
+    use std::time::Duration;
+
+    /// A backend which holds a single password, found after `delay`.
+    struct Stub {
+        name: &'static str,
+        password: Option<&'static str>,
+        delay: Duration,
+    }
+
+    impl Backend for Stub {
+        fn password(&self, _: &str, _: &str) -> Result<String, PwvltError> {
+            thread::sleep(self.delay);
+            self.password
+                .map(String::from)
+                .ok_or(PwvltError::PasswordNotFound)
+        }
+
+        fn set_password(&self, slot: usize, _: &str, _: &str, _: &str) -> Result<(), PwvltError> {
+            // the stubs are read-only
+            Err(PwvltError::ReadOnlySlot { slot })
+        }
+
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn slots(&self) -> Result<Vec<Slot>, PwvltError> {
+            Ok(Vec::new())
+        }
+    }
+
+    /// Records the backends of each `LookupConflict`.
+    #[derive(Default)]
+    struct Conflicts(Mutex<Vec<Vec<String>>>);
+
+    impl Observer for Conflicts {
+        fn notify(&self, event: &Event) {
+            if let Event::LookupConflict { backends, .. } = event {
+                let backends = backends.iter().map(|backend| backend.to_string());
+                self.0.lock().unwrap().push(backends.collect());
+            }
+        }
+    }
+
+    fn stub(name: &'static str, password: Option<&'static str>, delay: u64) -> Stub {
+        Stub {
+            name,
+            password,
+            delay: Duration::from_millis(delay),
+        }
+    }
+
+    fn vault(lookup: LookupStrategy, stubs: Vec<Stub>) -> (PasswordVault, Arc<Conflicts>) {
+        let conflicts = Arc::new(Conflicts::default());
+        let mut config = Config::default();
+        config.general.lookup = lookup;
+        let vault = PasswordVault {
+            config,
+            targets: vec![(BackendName::Keyring, None); stubs.len()],
+            backends: stubs
+                .into_iter()
+                .map(|stub| Arc::new(stub) as Arc<dyn Backend>)
+                .collect(),
+            observer: Arc::clone(&conflicts) as Arc<dyn Observer>,
+        };
+        (vault, conflicts)
+    }
+
+    #[test]
+    fn parallel_all_reports_conflicts() {
+        let (vault, conflicts) = vault(
+            LookupStrategy::ParallelAll,
+            vec![
+                stub("slow", Some("first"), 50),
+                stub("empty", None, 0),
+                stub("fast", Some("second"), 0),
+            ],
+        );
+
+        // the first backend in lookup order wins, even if it answers last
+        assert_eq!(vault.password("github", "alice").unwrap(), "first");
+        assert_eq!(*conflicts.0.lock().unwrap(), [["slow", "fast"]]);
+    }
+
+    #[test]
+    fn parallel_all_without_conflict() {
+        let (vault, conflicts) = vault(
+            LookupStrategy::ParallelAll,
+            vec![stub("a", Some("same"), 20), stub("b", Some("same"), 0)],
+        );
+
+        assert_eq!(vault.password("github", "alice").unwrap(), "same");
+        assert!(conflicts.0.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn parallel_first_hit_returns_the_fastest() {
+        let (vault, conflicts) = vault(
+            LookupStrategy::ParallelFirstHit,
+            vec![
+                stub("slow", Some("slow"), 200),
+                stub("empty", None, 0),
+                stub("fast", Some("fast"), 20),
+            ],
+        );
+
+        assert_eq!(vault.password("github", "alice").unwrap(), "fast");
+        assert!(conflicts.0.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn parallel_misses_are_reported_in_lookup_order() {
+        let (vault, _) = vault(
+            LookupStrategy::ParallelAll,
+            vec![stub("a", None, 20), stub("b", None, 0)],
+        );
+
+        match vault.password("github", "alice") {
+            Err(PwvltError::NotFoundAnywhere { attempts }) => {
+                let backends: Vec<_> = attempts.iter().map(|a| a.backend.as_str()).collect();
+                assert_eq!(backends, ["a", "b"]);
+            }
+            _ => panic!("Expected NotFoundAnywhere"),
+        }
+    }
+
+    fn routed_vault() -> PasswordVault {
+        let route = |pattern: &str, backend, serial: Option<&str>| crate::config::Route {
+            pattern: pattern.into(),
+            backend,
+            serial: serial.map(String::from),
+        };
+        let config = Config {
+            routes: vec![
+                route("work-*", BackendName::Pass, None),
+                route("*-nk", BackendName::Nitrokey, Some("0x00000002")),
+                route("shared-*", BackendName::Nitrokey, None),
+            ],
+            ..Default::default()
+        };
+        let serial = |serial: &str| serial.parse().ok();
+        PasswordVault {
+            config,
+            targets: vec![
+                (BackendName::Keyring, None),
+                (BackendName::Nitrokey, serial("0x00000001")),
+                (BackendName::Nitrokey, serial("0x00000002")),
+                (BackendName::Pass, None),
+            ],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn routed_backends_are_queried_first() {
+        let vault = routed_vault();
+
+        assert_eq!(vault.lookup_order("github"), [0, 1, 2, 3]);
+        assert_eq!(vault.lookup_order("work-mail"), [3, 0, 1, 2]);
+        assert_eq!(vault.lookup_order("mail-nk"), [2, 0, 1, 3]);
+        assert_eq!(vault.lookup_order("shared-nk"), [2, 1, 0, 3]);
+        assert_eq!(vault.lookup_order("work-nk"), [3, 2, 0, 1]);
+    }
+
+    #[test]
+    fn services_are_written_to_the_first_route() {
+        let vault = routed_vault();
+
+        assert_eq!(vault.route("github"), None);
+        assert_eq!(vault.route("work-mail"), Some(3));
+        assert_eq!(vault.route("mail-nk"), Some(2));
+        assert_eq!(vault.route("shared-mail"), Some(1));
+    }
+}
@@ -0,0 +1,53 @@
+//! A private session bus for the tests of the D-Bus backends.
+
+use std::env;
+use std::io::{BufRead, BufReader};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Mutex, MutexGuard};
+
+/// Every test gets its own bus, but the tests can't run at the same time
+/// since the bus is picked through an environment variable.
+static BUS_LOCK: Mutex<()> = Mutex::new(());
+
+/// A `dbus-daemon` serving as the session bus of the process, until it's
+/// dropped.
+pub struct SessionBus {
+    dbus: Child,
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl Drop for SessionBus {
+    fn drop(&mut self) {
+        let _ = self.dbus.kill();
+    }
+}
+
+impl SessionBus {
+    /// Starts a bus, or returns `None` if `dbus-daemon` can't be started.
+    pub fn start() -> Option<SessionBus> {
+        let lock = BUS_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        let dbus = Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .spawn()
+            .ok()?;
+        let mut bus = SessionBus { dbus, _lock: lock };
+        let mut address = String::new();
+        BufReader::new(bus.dbus.stdout.take()?)
+            .read_line(&mut address)
+            .ok()?;
+        // the backends and the services they talk to connect to the session
+        // bus named by this variable
+        env::set_var("DBUS_SESSION_BUS_ADDRESS", address.trim());
+        Some(bus)
+    }
+}
+
+/// Passes through what a test needs, or reports that the test is skipped
+/// because `daemons` aren't available.
+pub fn or_skip<T>(started: Option<T>, daemons: &str) -> Option<T> {
+    if started.is_none() {
+        eprintln!("Skipping, {} isn't available.", daemons);
+    }
+    started
+}
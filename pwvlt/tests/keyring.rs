@@ -0,0 +1,303 @@
+//! Tests of `KeyringBackend` against a throwaway gnome-keyring, running on a
+//! private session bus. They are skipped when `dbus-daemon` or
+//! `gnome-keyring-daemon` aren't installed.
+
+mod common;
+
+use common::SessionBus;
+use pwvlt::{Backend, Keyring, KeyringBackend, PwvltError, Relock};
+use secret_service::{EncryptionType, SecretService};
+
+use std::env;
+use std::fs::{create_dir_all, remove_dir_all};
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{self, Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+static BUS_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// A session bus and a gnome-keyring whose login keyring is unlocked. Both
+/// are stopped when the test is done.
+struct SecretServiceBus {
+    keyring: Child,
+    home: PathBuf,
+    _bus: SessionBus,
+}
+
+impl Drop for SecretServiceBus {
+    fn drop(&mut self) {
+        let _ = self.keyring.kill();
+        let _ = remove_dir_all(&self.home);
+    }
+}
+
+impl SecretServiceBus {
+    fn start() -> Option<SecretServiceBus> {
+        let bus = SessionBus::start()?;
+        let home = env::temp_dir().join(format!(
+            "pwvlt-keyring-{}-{}",
+            process::id(),
+            BUS_COUNT.fetch_add(1, Ordering::SeqCst)
+        ));
+        create_dir_all(&home).ok()?;
+        let keyring = Command::new("gnome-keyring-daemon")
+            .args(["--foreground", "--unlock", "--components=secrets"])
+            .env("HOME", &home)
+            .env("XDG_DATA_HOME", home.join("data"))
+            .env("XDG_RUNTIME_DIR", &home)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()
+            .ok()?;
+        let mut bus = SecretServiceBus {
+            keyring,
+            home,
+            _bus: bus,
+        };
+        // the password of the login keyring, which is created on the fly
+        bus.keyring.stdin.take()?.write_all(b"pwvlt\n").ok()?;
+
+        let started = Instant::now();
+        while started.elapsed() < Duration::from_secs(10) {
+            let ready = SecretService::new(EncryptionType::Dh)
+                .and_then(|ss| ss.get_default_collection().map(|_| ()));
+            if ready.is_ok() {
+                return Some(bus);
+            }
+            sleep(Duration::from_millis(100));
+        }
+        None
+    }
+}
+
+/// Starts a bus for a single test, or returns `None` if it can't be started.
+fn bus() -> Option<SecretServiceBus> {
+    common::or_skip(
+        SecretServiceBus::start(),
+        "dbus-daemon or gnome-keyring-daemon",
+    )
+}
+
+/// Stores an item the way another application would, without pwvlt's
+/// `application` attribute.
+fn create_foreign_item(service: &str, username: &str, password: &str) {
+    let ss = SecretService::new(EncryptionType::Dh).unwrap();
+    let collection = ss.get_default_collection().unwrap();
+    collection
+        .create_item(
+            "Foreign item",
+            vec![("service", service), ("username", username)],
+            password.as_bytes(),
+            true,
+            "text/plain",
+        )
+        .unwrap();
+}
+
+fn is_locked() -> bool {
+    let ss = SecretService::new(EncryptionType::Dh).unwrap();
+    let collection = ss.get_default_collection().unwrap();
+    collection.is_locked().unwrap()
+}
+
+fn lock() {
+    let ss = SecretService::new(EncryptionType::Dh).unwrap();
+    ss.get_default_collection().unwrap().lock().unwrap();
+}
+
+fn new_slot(backend: &KeyringBackend) -> usize {
+    backend.slots().unwrap().len() - 1
+}
+
+#[test]
+fn set_password_then_read_it() {
+    let _bus = match bus() {
+        Some(bus) => bus,
+        None => return,
+    };
+    let backend = KeyringBackend::new().unwrap();
+    let slot = new_slot(&backend);
+    backend
+        .set_password(slot, "read.example", "alice", "hunter2")
+        .unwrap();
+    assert_eq!(
+        backend.password("read.example", "alice").unwrap(),
+        "hunter2"
+    );
+
+    // a new backend reads the slots from the keyring again
+    let backend = KeyringBackend::new().unwrap();
+    assert_eq!(
+        backend.password("read.example", "alice").unwrap(),
+        "hunter2"
+    );
+}
+
+#[test]
+fn missing_password_is_not_found() {
+    let _bus = match bus() {
+        Some(bus) => bus,
+        None => return,
+    };
+    let backend = KeyringBackend::new().unwrap();
+    match backend.password("missing.example", "nobody") {
+        Err(PwvltError::PasswordNotFound) => {}
+        res => panic!("Expected PasswordNotFound, got {:?}", res),
+    }
+}
+
+#[test]
+fn slots_list_written_entries_and_a_new_slot() {
+    let _bus = match bus() {
+        Some(bus) => bus,
+        None => return,
+    };
+    let backend = KeyringBackend::new().unwrap();
+    let slot = new_slot(&backend);
+    backend
+        .set_password(slot, "slots.example", "bob", "secret")
+        .unwrap();
+    let slots = backend.slots().unwrap();
+    assert!(slots
+        .iter()
+        .any(|slot| slot.service == "slots.example" && slot.username == "bob"));
+    assert_eq!(slots.last().unwrap().service, "<not programmed>");
+}
+
+#[test]
+fn writing_to_a_slot_replaces_its_entry() {
+    let _bus = match bus() {
+        Some(bus) => bus,
+        None => return,
+    };
+    let backend = KeyringBackend::new().unwrap();
+    let slot = new_slot(&backend);
+    backend
+        .set_password(slot, "replace.example", "carol", "old")
+        .unwrap();
+    let slot = backend
+        .slots()
+        .unwrap()
+        .iter()
+        .position(|slot| slot.service == "replace.example")
+        .unwrap();
+    backend
+        .set_password(slot, "replaced.example", "dave", "new")
+        .unwrap();
+    assert_eq!(backend.password("replaced.example", "dave").unwrap(), "new");
+    match backend.password("replace.example", "carol") {
+        Err(PwvltError::PasswordNotFound) => {}
+        res => panic!("Expected the old entry to be deleted, got {:?}", res),
+    }
+    let backend = KeyringBackend::new().unwrap();
+    assert!(!backend
+        .slots()
+        .unwrap()
+        .iter()
+        .any(|slot| slot.service == "replace.example"));
+}
+
+#[test]
+fn foreign_items_are_hidden_by_default() {
+    let _bus = match bus() {
+        Some(bus) => bus,
+        None => return,
+    };
+    create_foreign_item("foreign.example", "eve", "theirs");
+    let backend = KeyringBackend::new().unwrap();
+    assert!(!backend
+        .slots()
+        .unwrap()
+        .iter()
+        .any(|slot| slot.service == "foreign.example"));
+    assert!(backend.password("foreign.example", "eve").is_err());
+}
+
+#[test]
+fn included_foreign_items_are_read_only() {
+    let _bus = match bus() {
+        Some(bus) => bus,
+        None => return,
+    };
+    create_foreign_item("readonly.example", "frank", "theirs");
+    let config = Keyring {
+        include_foreign: true,
+        ..Default::default()
+    };
+    let backend = KeyringBackend::with_config(config).unwrap();
+    assert_eq!(
+        backend.password("readonly.example", "frank").unwrap(),
+        "theirs"
+    );
+    let slot = backend
+        .slots()
+        .unwrap()
+        .iter()
+        .position(|slot| slot.service == "readonly.example")
+        .unwrap();
+    match backend.set_password(slot, "mine.example", "frank", "mine") {
+        Err(PwvltError::ReadOnlySlot { .. }) => {}
+        res => panic!("Expected ReadOnlySlot, got {:?}", res),
+    }
+}
+
+#[test]
+fn relock_on_drop_locks_the_collection_again() {
+    let _bus = match bus() {
+        Some(bus) => bus,
+        None => return,
+    };
+    lock();
+    let config = Keyring {
+        relock: Relock::OnDrop,
+        ..Default::default()
+    };
+    let backend = KeyringBackend::with_config(config).unwrap();
+    assert!(backend.password("relock.example", "alice").is_err());
+    assert!(!is_locked());
+    drop(backend);
+    assert!(is_locked());
+}
+
+#[test]
+fn relock_idle_locks_the_collection_while_in_use() {
+    let _bus = match bus() {
+        Some(bus) => bus,
+        None => return,
+    };
+    lock();
+    let config = Keyring {
+        relock: Relock::OnDrop,
+        relock_idle: Some(1),
+        ..Default::default()
+    };
+    let backend = KeyringBackend::with_config(config).unwrap();
+    assert!(backend.password("idle.example", "alice").is_err());
+    assert!(!is_locked());
+    sleep(Duration::from_millis(2500));
+    assert!(is_locked());
+    drop(backend);
+}
+
+#[test]
+fn an_unlocked_collection_is_left_unlocked() {
+    let _bus = match bus() {
+        Some(bus) => bus,
+        None => return,
+    };
+    assert!(!is_locked());
+    let config = Keyring {
+        relock: Relock::OnDrop,
+        relock_idle: Some(1),
+        ..Default::default()
+    };
+    let backend = KeyringBackend::with_config(config).unwrap();
+    assert!(backend.password("unlocked.example", "alice").is_err());
+    sleep(Duration::from_millis(2500));
+    assert!(!is_locked());
+    drop(backend);
+    assert!(!is_locked());
+}
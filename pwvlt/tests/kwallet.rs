@@ -0,0 +1,262 @@
+//! Tests of `KWalletBackend` against a mocked kwalletd, served on a private
+//! session bus. They are skipped when `dbus-daemon` isn't installed.
+
+mod common;
+
+use common::SessionBus;
+use dbus::{BusType, Connection, ConnectionItem, Message, MessageItem, NameFlag};
+use pwvlt::{Backend, KWalletBackend, PwvltError};
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+
+/// The folders and entries of the mocked wallet. Entries are keyed by folder
+/// and name.
+#[derive(Default)]
+struct Wallet {
+    /// How many handles were opened and not closed yet.
+    handles: usize,
+    folders: BTreeSet<String>,
+    entries: BTreeMap<(String, String), String>,
+}
+
+fn string(items: &[MessageItem], i: usize) -> String {
+    match items.get(i) {
+        Some(MessageItem::Str(value)) => value.clone(),
+        item => panic!("Expected a string argument, got {:?}", item),
+    }
+}
+
+impl Wallet {
+    /// Answers a call of the `org.kde.KWallet` interface. The arguments are
+    /// the ones pwvlt passes, e.g. `entryList(handle, folder, appid)`.
+    fn reply(&mut self, method: &str, args: &[MessageItem]) -> MessageItem {
+        match method {
+            "isEnabled" => true.into(),
+            "networkWallet" => "kdewallet".into(),
+            "open" => {
+                self.handles += 1;
+                1i32.into()
+            }
+            "close" => {
+                self.handles -= 1;
+                0i32.into()
+            }
+            "hasFolder" => self.folders.contains(&string(args, 1)).into(),
+            "createFolder" => self.folders.insert(string(args, 1)).into(),
+            "entryList" => {
+                let folder = string(args, 1);
+                let names = self
+                    .entries
+                    .keys()
+                    .filter(|(f, _)| *f == folder)
+                    .map(|(_, name)| MessageItem::Str(name.clone()))
+                    .collect();
+                MessageItem::Array(names, "s".into())
+            }
+            "hasEntry" => self
+                .entries
+                .contains_key(&(string(args, 1), string(args, 2)))
+                .into(),
+            "readPassword" => self
+                .entries
+                .get(&(string(args, 1), string(args, 2)))
+                .cloned()
+                .unwrap_or_default()
+                .into(),
+            "writePassword" => {
+                let key = (string(args, 1), string(args, 2));
+                self.entries.insert(key, string(args, 3));
+                0i32.into()
+            }
+            "removeEntry" => {
+                self.entries.remove(&(string(args, 1), string(args, 2)));
+                0i32.into()
+            }
+            method => panic!("Unexpected call to {}", method),
+        }
+    }
+}
+
+/// A session bus on which a mocked kwalletd is served. Both are stopped when
+/// the test is done.
+struct MockKWallet {
+    wallet: Arc<Mutex<Wallet>>,
+    stop: Arc<AtomicBool>,
+    server: Option<JoinHandle<()>>,
+    _bus: SessionBus,
+}
+
+impl Drop for MockKWallet {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(server) = self.server.take() {
+            let _ = server.join();
+        }
+    }
+}
+
+impl MockKWallet {
+    fn start() -> Option<MockKWallet> {
+        let bus = SessionBus::start()?;
+        let wallet = Arc::new(Mutex::new(Wallet::default()));
+        let stop = Arc::new(AtomicBool::new(false));
+        let (ready_tx, ready_rx) = channel();
+        let server = {
+            let (wallet, stop) = (Arc::clone(&wallet), Arc::clone(&stop));
+            thread::spawn(move || {
+                let connection = Connection::get_private(BusType::Session).unwrap();
+                connection
+                    .register_name("org.kde.kwalletd5", NameFlag::ReplaceExisting as u32)
+                    .unwrap();
+                connection
+                    .register_object_path("/modules/kwalletd5")
+                    .unwrap();
+                ready_tx.send(()).unwrap();
+                for item in connection.iter(100) {
+                    if stop.load(Ordering::SeqCst) {
+                        return;
+                    }
+                    if let ConnectionItem::MethodCall(call) = item {
+                        let method = call.member().unwrap().to_string();
+                        let reply = wallet.lock().unwrap().reply(&method, &call.get_items());
+                        let reply = Message::new_method_return(&call).unwrap().append(reply);
+                        connection.send(reply).unwrap();
+                    }
+                }
+            })
+        };
+        ready_rx.recv().ok()?;
+        Some(MockKWallet {
+            wallet,
+            stop,
+            server: Some(server),
+            _bus: bus,
+        })
+    }
+
+    fn wallet(&self) -> MutexGuard<'_, Wallet> {
+        self.wallet.lock().unwrap()
+    }
+}
+
+/// Starts a mocked kwalletd for a single test, or returns `None` if it can't
+/// be started.
+fn kwallet() -> Option<MockKWallet> {
+    common::or_skip(MockKWallet::start(), "dbus-daemon")
+}
+
+#[test]
+fn set_password_then_read_it() {
+    let kwallet = match kwallet() {
+        Some(kwallet) => kwallet,
+        None => return,
+    };
+    let backend = KWalletBackend::new().unwrap();
+    backend
+        .set_password(0, "read.example", "alice", "hunter2")
+        .unwrap();
+    assert_eq!(
+        backend.password("read.example", "alice").unwrap(),
+        "hunter2"
+    );
+    let wallet = kwallet.wallet();
+    assert!(wallet.folders.contains("pwvlt"));
+    assert_eq!(
+        wallet.entries[&("pwvlt".to_string(), "read.example/alice".to_string())],
+        "hunter2"
+    );
+}
+
+#[test]
+fn missing_password_is_not_found() {
+    let _kwallet = match kwallet() {
+        Some(kwallet) => kwallet,
+        None => return,
+    };
+    let backend = KWalletBackend::new().unwrap();
+    match backend.password("missing.example", "nobody") {
+        Err(PwvltError::PasswordNotFound) => {}
+        res => panic!("Expected PasswordNotFound, got {:?}", res),
+    }
+}
+
+#[test]
+fn slots_are_sorted_and_end_with_a_new_slot() {
+    let _kwallet = match kwallet() {
+        Some(kwallet) => kwallet,
+        None => return,
+    };
+    let backend = KWalletBackend::new().unwrap();
+    backend.set_password(0, "b.example", "bob", "1").unwrap();
+    backend.set_password(1, "a.example", "alice", "2").unwrap();
+    let slots = backend.slots().unwrap();
+    let slots: Vec<_> = slots
+        .iter()
+        .map(|slot| (slot.service.as_str(), slot.username.as_str()))
+        .collect();
+    assert_eq!(
+        slots,
+        [
+            ("a.example", "alice"),
+            ("b.example", "bob"),
+            ("<not programmed>", "<not programmed>"),
+        ]
+    );
+}
+
+#[test]
+fn writing_to_a_slot_replaces_its_entry() {
+    let kwallet = match kwallet() {
+        Some(kwallet) => kwallet,
+        None => return,
+    };
+    let backend = KWalletBackend::new().unwrap();
+    backend
+        .set_password(0, "old.example", "carol", "old")
+        .unwrap();
+    backend
+        .set_password(0, "new.example", "dave", "new")
+        .unwrap();
+    assert_eq!(backend.password("new.example", "dave").unwrap(), "new");
+    assert!(backend.password("old.example", "carol").is_err());
+    assert_eq!(kwallet.wallet().entries.len(), 1);
+}
+
+#[test]
+fn wallet_is_closed_after_every_operation() {
+    let kwallet = match kwallet() {
+        Some(kwallet) => kwallet,
+        None => return,
+    };
+    let backend = KWalletBackend::new().unwrap();
+    backend
+        .set_password(0, "close.example", "frank", "1")
+        .unwrap();
+    backend.password("close.example", "frank").unwrap();
+    assert!(backend.password("missing.example", "nobody").is_err());
+    backend.slots().unwrap();
+    assert_eq!(kwallet.wallet().handles, 0);
+}
+
+#[test]
+fn services_with_a_slash_are_rejected() {
+    let _kwallet = match kwallet() {
+        Some(kwallet) => kwallet,
+        None => return,
+    };
+    let backend = KWalletBackend::new().unwrap();
+    match backend.set_password(0, "a/b", "eve", "secret") {
+        Err(PwvltError::InvalidCharacter { field, .. }) => assert_eq!(field, "service"),
+        res => panic!("Expected InvalidCharacter, got {:?}", res),
+    }
+    // the entry of `b/c` on `a` has the same name
+    backend.set_password(0, "a", "b/c", "secret").unwrap();
+    match backend.password("a/b", "c") {
+        Err(PwvltError::InvalidCharacter { field, .. }) => assert_eq!(field, "service"),
+        res => panic!("Expected InvalidCharacter, got {:?}", res),
+    }
+}